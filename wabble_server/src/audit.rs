@@ -0,0 +1,53 @@
+use std::{collections::VecDeque, sync::Mutex, time::SystemTime};
+
+use uuid::Uuid;
+
+use crate::room::MessagePersona;
+
+const AUDIT_LOG_LEN: usize = 1000; // only kept in memory, the oldest entries fall off
+
+#[derive(Debug, Clone, serde::Serialize)]
+#[serde(tag = "action", rename_all = "snake_case")]
+pub enum AuditAction {
+    DeleteMessage {
+        room: mtid::Ttid,
+        message_id: u64,
+        author: MessagePersona,
+        message: String,
+        had_drawing: bool,
+    },
+}
+
+#[derive(Debug, Clone)]
+pub struct AuditEntry {
+    pub at: SystemTime,
+    pub actor: Uuid, // session that did it
+    pub moderator: bool,
+    pub action: AuditAction,
+}
+
+/// Who did what, for the things that can't be undone.
+#[derive(Debug, Default)]
+pub struct AuditLog(Mutex<VecDeque<AuditEntry>>);
+
+impl AuditLog {
+    pub fn record(&self, actor: Uuid, moderator: bool, action: AuditAction) {
+        tracing::info!("audit: {actor} (moderator: {moderator}) {action:?}");
+
+        let mut entries = self.0.lock().unwrap();
+        if entries.len() >= AUDIT_LOG_LEN {
+            entries.pop_front();
+        }
+        entries.push_back(AuditEntry {
+            at: SystemTime::now(),
+            actor,
+            moderator,
+            action,
+        });
+    }
+
+    /// Oldest first.
+    pub fn entries(&self) -> Vec<AuditEntry> {
+        self.0.lock().unwrap().iter().cloned().collect()
+    }
+}
@@ -0,0 +1,695 @@
+use std::{
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use rand::Rng;
+
+use crate::{
+    global::GlobalState,
+    responses,
+    room::{Persona, Room},
+    session::{self, BanTarget, Session, SessionEvent},
+};
+
+/// Who gets to run a command, each level can also run everything below it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Permission {
+    Everyone,
+    RoomOwner, // moderators count as owners of every room
+    Moderator,
+}
+
+/// What the one running a command gets back.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Reply {
+    Private(String), // only they see it
+    Room(String),    // everyone in the room sees it as a system message, for what the server says
+    Say(String),     // sent on as a normal message from them, filter and all
+}
+
+/// Everything a command gets to look at or change.
+pub struct Context<'a> {
+    pub global: &'a GlobalState,
+    pub session: &'a Session,
+    pub persona: &'a Arc<Mutex<Persona>>,
+    pub room: &'a Room,          // the one the command was typed in
+    pub member_rooms: Vec<Room>, // everywhere the persona is seen, for name collisions
+}
+
+impl Context<'_> {
+    pub fn permission(&self) -> Permission {
+        if self.session.is_moderator() {
+            Permission::Moderator
+        } else if self.room.is_owner(self.session.id) {
+            Permission::RoomOwner
+        } else {
+            Permission::Everyone
+        }
+    }
+
+    fn name(&self) -> String {
+        self.persona.lock().unwrap().name.clone()
+    }
+
+    /// Same rules as the `Persona` opcode, the new persona comes back.
+    fn update_persona(&self, request: responses::Persona) -> Persona {
+        let current = self.persona.lock().unwrap().clone();
+        *self.persona.lock().unwrap() =
+            Persona::from_response(request, current, &self.global.filter);
+        for room in &self.member_rooms {
+            room.check_collisions(self.persona.clone());
+        }
+        self.persona.lock().unwrap().clone()
+    }
+
+    /// The member whose name `args` starts with, longest name first since names can have
+    /// spaces, and whatever comes after it.
+    fn target<'b>(&self, args: &'b str) -> Result<(Persona, &'b str), Reply> {
+        let personas: Vec<Persona> = self
+            .room
+            .personas
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|p| p.lock().unwrap().clone())
+            .collect();
+
+        let mut matches: Vec<(&Persona, &str)> = personas
+            .iter()
+            .filter_map(|p| Some((p, strip_name(args, &p.name)?)))
+            .collect();
+        let longest = matches.iter().map(|(p, _)| p.name.len()).max().unwrap_or(0);
+        matches.retain(|(p, _)| p.name.len() == longest);
+
+        match matches.as_slice() {
+            [] => Err(Reply::Private(
+                "Nobody in this room goes by that name".to_string(),
+            )),
+            [(persona, rest)] => Ok(((*persona).clone(), rest.trim())),
+            _ => Err(Reply::Private(
+                "More than one person goes by that name".to_string(),
+            )),
+        }
+    }
+}
+
+/// `args` without the name it starts with, ignoring case.
+fn strip_name<'a>(args: &'a str, name: &str) -> Option<&'a str> {
+    let mut chars = args.chars();
+    for expected in name.chars() {
+        let c = chars.next()?;
+        if !c.to_lowercase().eq(expected.to_lowercase()) {
+            return None;
+        }
+    }
+    let rest = chars.as_str();
+    (rest.is_empty() || rest.starts_with(' ')).then_some(rest)
+}
+
+pub trait Command: Sync {
+    /// Without the slash.
+    fn name(&self) -> &'static str;
+    /// One line, usage first.
+    fn help(&self) -> &'static str;
+    fn permission(&self) -> Permission {
+        Permission::Everyone
+    }
+    /// Whether it can put something in front of the whole room, muted people can't run those.
+    fn broadcasts(&self) -> bool {
+        false
+    }
+    fn run(&self, ctx: &Context, args: &str) -> Reply;
+}
+
+static COMMANDS: &[&dyn Command] = &[
+    &Help, &Nick, &Color, &Me, &Roll, &Who, &Lock, &Unlock, &Kick, &Mute, &Unmute, &Ban,
+];
+
+/// Runs a message starting with `/`, nothing if it's just a message. Messages starting with `//`
+/// aren't commands either, the socket sends those with the first slash taken off.
+pub fn run(ctx: &Context, message: &str) -> Option<Reply> {
+    let line = message.strip_prefix('/')?;
+    if line.starts_with('/') {
+        return None;
+    }
+
+    let (name, args) = line.split_once(' ').unwrap_or((line, ""));
+    let name = name.to_lowercase();
+    let Some(command) = COMMANDS.iter().find(|c| c.name() == name) else {
+        return Some(Reply::Private(format!(
+            "There's no /{name} command, try /help"
+        )));
+    };
+    if ctx.permission() < command.permission() {
+        tracing::debug!("{} isn't allowed to run /{name}", ctx.session.id);
+        return Some(Reply::Private(format!("You're not allowed to use /{name}")));
+    }
+    if command.broadcasts() && ctx.global.is_muted(ctx.session.ip) {
+        tracing::debug!("{} is muted, not running /{name}", ctx.session.id);
+        return Some(Reply::Private("You're muted".to_string()));
+    }
+
+    tracing::debug!("{} ran /{name}", ctx.session.id);
+    Some(command.run(ctx, args.trim()))
+}
+
+struct Help;
+
+impl Command for Help {
+    fn name(&self) -> &'static str {
+        "help"
+    }
+
+    fn help(&self) -> &'static str {
+        "/help [command] - list the commands you can use, or explain one"
+    }
+
+    fn run(&self, ctx: &Context, args: &str) -> Reply {
+        let allowed = COMMANDS
+            .iter()
+            .filter(|c| c.permission() <= ctx.permission());
+        let name = args.trim_start_matches('/').to_lowercase();
+        if !name.is_empty() {
+            return match allowed.clone().find(|c| c.name() == name) {
+                Some(command) => Reply::Private(command.help().to_string()),
+                None => Reply::Private(format!("There's no /{name} command")),
+            };
+        }
+
+        let lines: Vec<&str> = allowed.map(|c| c.help()).collect();
+        Reply::Private(lines.join("\n"))
+    }
+}
+
+struct Nick;
+
+impl Command for Nick {
+    fn name(&self) -> &'static str {
+        "nick"
+    }
+
+    fn help(&self) -> &'static str {
+        "/nick <name> - change your name"
+    }
+
+    fn broadcasts(&self) -> bool {
+        true
+    }
+
+    fn run(&self, ctx: &Context, args: &str) -> Reply {
+        let old = ctx.name();
+        let persona = ctx.update_persona(responses::Persona {
+            name: Some(args.to_string()),
+            color: None,
+        });
+        if persona.name == old {
+            return Reply::Private("You can't use that name".to_string());
+        }
+        Reply::Room(format!("{old} is now known as {}", persona.name))
+    }
+}
+
+struct Color;
+
+impl Command for Color {
+    fn name(&self) -> &'static str {
+        "color"
+    }
+
+    fn help(&self) -> &'static str {
+        "/color <rrggbb> - change your color"
+    }
+
+    fn run(&self, ctx: &Context, args: &str) -> Reply {
+        let mut color = args.trim_start_matches('#').to_uppercase();
+        if color.len() == 6 {
+            color.push_str("FF");
+        }
+        if color.len() != 8 || !color.chars().all(|c| c.is_ascii_hexdigit()) {
+            return Reply::Private("Colors look like 3366ff".to_string());
+        }
+
+        let persona = ctx.update_persona(responses::Persona {
+            name: None,
+            color: Some(color.clone()),
+        });
+        if persona.color != color {
+            return Reply::Private("You can't use that color".to_string());
+        }
+        Reply::Private(format!("Your color is now {color}"))
+    }
+}
+
+struct Me;
+
+impl Command for Me {
+    fn name(&self) -> &'static str {
+        "me"
+    }
+
+    fn help(&self) -> &'static str {
+        "/me <action> - say what you're doing"
+    }
+
+    fn broadcasts(&self) -> bool {
+        true
+    }
+
+    fn run(&self, _ctx: &Context, args: &str) -> Reply {
+        if args.is_empty() {
+            return Reply::Private(self.help().to_string());
+        }
+
+        // leaves room for the asterisks within the message length limit
+        let action: String = args.chars().take(163).collect();
+        Reply::Say(format!("*{action}*"))
+    }
+}
+
+struct Roll;
+
+impl Command for Roll {
+    fn name(&self) -> &'static str {
+        "roll"
+    }
+
+    fn help(&self) -> &'static str {
+        "/roll [NdM] - roll N dice with M sides, 1d6 if not given"
+    }
+
+    fn broadcasts(&self) -> bool {
+        true
+    }
+
+    fn run(&self, ctx: &Context, args: &str) -> Reply {
+        let dice = if args.is_empty() { "1d6" } else { args };
+        let parsed = dice
+            .to_lowercase()
+            .split_once('d')
+            .and_then(|(n, m)| {
+                let n = if n.is_empty() { Ok(1) } else { n.parse() };
+                Some((n.ok()?, m.parse().ok()?))
+            })
+            .filter(|&(n, m): &(u32, u32)| (1..=20).contains(&n) && (2..=1000).contains(&m));
+        let Some((n, m)) = parsed else {
+            return Reply::Private("Up to 20 dice with 2 to 1000 sides, like 2d6".to_string());
+        };
+
+        let mut rng = rand::rng();
+        let rolls: Vec<u32> = (0..n).map(|_| rng.random_range(1..=m)).collect();
+        let total: u32 = rolls.iter().sum();
+        let name = ctx.name();
+        if n == 1 {
+            return Reply::Room(format!("{name} rolled {n}d{m}: {total}"));
+        }
+        let rolls: Vec<String> = rolls.iter().map(|r| r.to_string()).collect();
+        Reply::Room(format!(
+            "{name} rolled {n}d{m}: {} = {total}",
+            rolls.join(" + ")
+        ))
+    }
+}
+
+struct Who;
+
+impl Command for Who {
+    fn name(&self) -> &'static str {
+        "who"
+    }
+
+    fn help(&self) -> &'static str {
+        "/who - list who's in the room"
+    }
+
+    fn run(&self, ctx: &Context, _args: &str) -> Reply {
+        let names: Vec<String> = ctx
+            .room
+            .personas
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|p| p.lock().unwrap().name.clone())
+            .collect();
+        let mut reply = format!(
+            "In {} ({}): {}",
+            ctx.room.name(),
+            names.len(),
+            names.join(", ")
+        );
+        let spectators = ctx.room.current_spectators();
+        if spectators > 0 {
+            reply.push_str(&format!(", and {spectators} watching"));
+        }
+        Reply::Private(reply)
+    }
+}
+
+fn set_locked(ctx: &Context, locked: bool) -> Reply {
+    if ctx.room.is_public {
+        return Reply::Private("Public rooms can't be locked".to_string());
+    }
+    ctx.room.set_locked(locked);
+    let message = if locked {
+        "The room is now locked, nobody else can join"
+    } else {
+        "The room is now unlocked"
+    };
+    Reply::Room(message.to_string())
+}
+
+struct Lock;
+
+impl Command for Lock {
+    fn name(&self) -> &'static str {
+        "lock"
+    }
+
+    fn help(&self) -> &'static str {
+        "/lock - keep anyone else from joining"
+    }
+
+    fn permission(&self) -> Permission {
+        Permission::RoomOwner
+    }
+
+    fn broadcasts(&self) -> bool {
+        true
+    }
+
+    fn run(&self, ctx: &Context, _args: &str) -> Reply {
+        set_locked(ctx, true)
+    }
+}
+
+struct Unlock;
+
+impl Command for Unlock {
+    fn name(&self) -> &'static str {
+        "unlock"
+    }
+
+    fn help(&self) -> &'static str {
+        "/unlock - let people join again"
+    }
+
+    fn permission(&self) -> Permission {
+        Permission::RoomOwner
+    }
+
+    fn broadcasts(&self) -> bool {
+        true
+    }
+
+    fn run(&self, ctx: &Context, _args: &str) -> Reply {
+        set_locked(ctx, false)
+    }
+}
+
+struct Kick;
+
+impl Command for Kick {
+    fn name(&self) -> &'static str {
+        "kick"
+    }
+
+    fn help(&self) -> &'static str {
+        "/kick <name> [reason] - kick someone from this room"
+    }
+
+    fn permission(&self) -> Permission {
+        Permission::Moderator
+    }
+
+    fn run(&self, ctx: &Context, args: &str) -> Reply {
+        let (persona, reason) = match ctx.target(args) {
+            Ok(target) => target,
+            Err(reply) => return reply,
+        };
+        let Some(target) = ctx.global.get_session(persona.id) else {
+            return Reply::Private(format!("{} just left", persona.name));
+        };
+
+        tracing::info!(
+            "moderator {} kicked {} from room {}",
+            ctx.session.id,
+            target.id,
+            ctx.room.id.id()
+        );
+        target.notify(SessionEvent::Kicked {
+            room: Some(ctx.room.id),
+            reason: (!reason.is_empty()).then(|| reason.to_string()),
+        });
+        Reply::Private(format!("Kicked {}", persona.name))
+    }
+}
+
+struct Mute;
+
+impl Command for Mute {
+    fn name(&self) -> &'static str {
+        "mute"
+    }
+
+    fn help(&self) -> &'static str {
+        "/mute <name> <seconds> [reason] - keep someone quiet for a while, 0 seconds unmutes"
+    }
+
+    fn permission(&self) -> Permission {
+        Permission::Moderator
+    }
+
+    fn run(&self, ctx: &Context, args: &str) -> Reply {
+        let (persona, rest) = match ctx.target(args) {
+            Ok(target) => target,
+            Err(reply) => return reply,
+        };
+        let (seconds, reason) = rest.split_once(' ').unwrap_or((rest, ""));
+        let Ok(seconds) = seconds.parse::<u64>() else {
+            return Reply::Private(self.help().to_string());
+        };
+        set_muted(ctx, persona, Duration::from_secs(seconds), reason.trim())
+    }
+}
+
+struct Unmute;
+
+impl Command for Unmute {
+    fn name(&self) -> &'static str {
+        "unmute"
+    }
+
+    fn help(&self) -> &'static str {
+        "/unmute <name> - let someone talk again"
+    }
+
+    fn permission(&self) -> Permission {
+        Permission::Moderator
+    }
+
+    fn run(&self, ctx: &Context, args: &str) -> Reply {
+        match ctx.target(args) {
+            Ok((persona, _)) => set_muted(ctx, persona, Duration::ZERO, ""),
+            Err(reply) => reply,
+        }
+    }
+}
+
+/// A zero duration unmutes. The room hears about it from the target's own socket, same as kicks
+/// and bans, so this only answers the moderator.
+fn set_muted(ctx: &Context, persona: Persona, duration: Duration, reason: &str) -> Reply {
+    let Some(target) = ctx.global.get_session(persona.id) else {
+        return Reply::Private(format!("{} just left", persona.name));
+    };
+
+    tracing::info!(
+        "moderator {} muted {} for {}s",
+        ctx.session.id,
+        target.id,
+        duration.as_secs()
+    );
+    let duration = ctx.global.mute(target.ip, duration);
+    target.notify(SessionEvent::Muted {
+        duration,
+        reason: (!reason.is_empty()).then(|| reason.to_string()),
+    });
+    if duration.is_zero() {
+        return Reply::Private(format!("Unmuted {}", persona.name));
+    }
+    Reply::Private(format!(
+        "Muted {} for {}s",
+        persona.name,
+        duration.as_secs()
+    ))
+}
+
+struct Ban;
+
+impl Command for Ban {
+    fn name(&self) -> &'static str {
+        "ban"
+    }
+
+    fn help(&self) -> &'static str {
+        "/ban <name> [seconds] [reason] - keep someone off the server, forever if no time is given"
+    }
+
+    fn permission(&self) -> Permission {
+        Permission::Moderator
+    }
+
+    fn run(&self, ctx: &Context, args: &str) -> Reply {
+        let (persona, rest) = match ctx.target(args) {
+            Ok(target) => target,
+            Err(reply) => return reply,
+        };
+        // the duration is optional, anything that isn't one is the start of the reason
+        let (first, after) = rest.split_once(' ').unwrap_or((rest, ""));
+        let (seconds, reason) = match first.parse::<u64>() {
+            Ok(seconds) => (Some(seconds), after.trim()),
+            Err(_) => (None, rest),
+        };
+        if seconds == Some(0) {
+            return Reply::Private(self.help().to_string());
+        }
+        if ctx.global.get_session(persona.id).is_none() {
+            return Reply::Private(format!("{} just left", persona.name));
+        }
+
+        tracing::info!("moderator {} banned {}", ctx.session.id, persona.id);
+        ctx.global.ban(session::Ban::new(
+            BanTarget::Session(persona.id),
+            seconds.map(Duration::from_secs),
+            (!reason.is_empty()).then(|| reason.to_string()),
+        ));
+        match seconds {
+            Some(seconds) => Reply::Private(format!("Banned {} for {seconds}s", persona.name)),
+            None => Reply::Private(format!("Banned {}", persona.name)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use uuid::Uuid;
+
+    use super::*;
+    use crate::settings::Settings;
+
+    fn run_as(muted: bool, message: &str) -> Option<Reply> {
+        let global = GlobalState::new(Settings::default());
+        let id = Uuid::new_v4();
+        let persona = Arc::new(Mutex::new(Persona::new(id)));
+        let (session, _rx) = Session::new(id, "192.0.2.1".parse().unwrap(), persona.clone());
+        if muted {
+            global.mute(session.ip, Duration::from_secs(60));
+        }
+        let room = global.insert_room(|room| Room::new_private(room, id));
+
+        let ctx = Context {
+            global: &global,
+            session: &session,
+            persona: &persona,
+            room: &room,
+            member_rooms: vec![room.clone()],
+        };
+        run(&ctx, message)
+    }
+
+    #[test]
+    fn plain_messages_are_not_commands() {
+        assert_eq!(run_as(false, "hello"), None);
+        assert_eq!(run_as(false, "//roll"), None);
+    }
+
+    #[test]
+    fn me_is_said_by_whoever_ran_it() {
+        assert_eq!(
+            run_as(false, "/me waves"),
+            Some(Reply::Say("*waves*".to_string()))
+        );
+    }
+
+    #[test]
+    fn muted_people_cannot_broadcast() {
+        let muted = Some(Reply::Private("You're muted".to_string()));
+        for command in ["/me waves", "/roll", "/nick someone", "/lock"] {
+            assert_eq!(run_as(true, command), muted, "{command}");
+        }
+        assert!(matches!(run_as(true, "/help"), Some(Reply::Private(_))));
+        assert!(matches!(run_as(false, "/roll"), Some(Reply::Room(_))));
+    }
+
+    /// A moderator running `message` on someone named "troll" in the same room.
+    fn moderate(message: &str) -> (GlobalState, Uuid, Reply) {
+        let global = GlobalState::new(Settings::default());
+        let id = Uuid::new_v4();
+        let persona = Arc::new(Mutex::new(Persona::new(id)));
+        let (session, _rx) = Session::new(id, "192.0.2.1".parse().unwrap(), persona.clone());
+        session.set_moderator(true);
+        let room = global.insert_room(|room| Room::new_private(room, id));
+
+        let troll_id = Uuid::new_v4();
+        let mut troll = Persona::new(troll_id);
+        troll.name = "troll".to_string();
+        let troll = Arc::new(Mutex::new(troll));
+        let (troll_session, _troll_rx) =
+            Session::new(troll_id, "192.0.2.2".parse().unwrap(), troll.clone());
+        let _guard = global.session_guard(&Arc::new(troll_session));
+        room.personas.lock().unwrap().push(troll);
+
+        let ctx = Context {
+            global: &global,
+            session: &session,
+            persona: &persona,
+            room: &room,
+            member_rooms: vec![room.clone()],
+        };
+        let reply = run(&ctx, message).unwrap();
+        (global, troll_id, reply)
+    }
+
+    #[test]
+    fn zero_second_mutes_unmute() {
+        let troll: std::net::IpAddr = "192.0.2.2".parse().unwrap();
+        let (global, _, reply) = moderate("/mute troll 60 spam");
+        assert_eq!(reply, Reply::Private("Muted troll for 60s".to_string()));
+        assert!(global.is_muted(troll));
+
+        for command in ["/mute troll 0", "/unmute troll"] {
+            let (global, _, reply) = moderate(command);
+            assert_eq!(
+                reply,
+                Reply::Private("Unmuted troll".to_string()),
+                "{command}"
+            );
+            assert!(!global.is_muted(troll), "{command}");
+        }
+    }
+
+    #[test]
+    fn bans_take_an_optional_duration() {
+        let (global, id, reply) = moderate("/ban troll 60 spam");
+        assert_eq!(reply, Reply::Private("Banned troll for 60s".to_string()));
+        let ban = &global.get_bans()[0];
+        assert_eq!(ban.target, BanTarget::Session(id));
+        assert!(ban.expires_at.is_some());
+        assert_eq!(ban.reason.as_deref(), Some("spam"));
+
+        let (global, _, reply) = moderate("/ban troll being rude");
+        assert_eq!(reply, Reply::Private("Banned troll".to_string()));
+        let ban = &global.get_bans()[0];
+        assert_eq!(ban.expires_at, None);
+        assert_eq!(ban.reason.as_deref(), Some("being rude"));
+
+        let (global, _, _) = moderate("/ban troll 0");
+        assert!(global.get_bans().is_empty());
+    }
+
+    #[test]
+    fn names_are_stripped_whole() {
+        assert_eq!(strip_name("Alice hi", "alice"), Some(" hi"));
+        assert_eq!(strip_name("alice", "Alice"), Some(""));
+        assert_eq!(strip_name("alicette hi", "alice"), None);
+    }
+}
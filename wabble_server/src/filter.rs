@@ -0,0 +1,406 @@
+use std::{
+    fs::File,
+    io::Write,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, RwLock},
+    time::{Duration, SystemTime},
+};
+
+use unicode_normalization::{UnicodeNormalization, char::is_combining_mark};
+
+use crate::{global::GlobalState, settings::FilterSettings};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MatchMode {
+    #[default]
+    Substring,
+    Word,
+    Regex, // matched against the normalized text, so write them in lowercase and without leetspeak
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FilterAction {
+    Reject,
+    Mask,
+    ShadowDrop, // the sender sees their message, nobody else does
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FilterScope {
+    #[default]
+    All,
+    Names,
+    Messages,
+}
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct FilterRule {
+    pub pattern: String,
+    #[serde(default)]
+    pub mode: MatchMode,
+    pub action: FilterAction,
+    #[serde(default)]
+    pub scope: FilterScope,
+}
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct WordList {
+    #[serde(default)]
+    pub rules: Vec<FilterRule>,
+}
+
+impl Default for WordList {
+    fn default() -> Self {
+        // what used to be hardcoded in the persona
+        let rules = ["system", "server", "admin"]
+            .into_iter()
+            .map(|pattern| FilterRule {
+                pattern: pattern.to_string(),
+                mode: MatchMode::Substring,
+                action: FilterAction::Reject,
+                scope: FilterScope::Names,
+            })
+            .collect();
+
+        Self { rules }
+    }
+}
+
+impl WordList {
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        Ok(toml::from_str(&std::fs::read_to_string(path)?)?)
+    }
+
+    pub fn create_file(path: &Path) -> anyhow::Result<()> {
+        let mut file = File::create(path)?;
+        file.write_all(toml::to_string_pretty(&Self::default())?.as_bytes())?;
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Verdict {
+    Pass(String), // possibly masked
+    Reject,
+    ShadowDrop,
+}
+
+#[derive(Debug)]
+struct CompiledRule {
+    regex: regex::Regex,
+    action: FilterAction,
+    scope: FilterScope,
+}
+
+#[derive(Debug, Default)]
+pub struct Filter {
+    rules: Vec<CompiledRule>,
+}
+
+impl Filter {
+    pub fn compile(list: &WordList) -> anyhow::Result<Self> {
+        let mut rules = Vec::with_capacity(list.rules.len());
+        for rule in &list.rules {
+            let pattern = match rule.mode {
+                MatchMode::Substring => regex::escape(&Folded::new(&rule.pattern).text),
+                MatchMode::Word => {
+                    format!(r"\b{}\b", regex::escape(&Folded::new(&rule.pattern).text))
+                }
+                MatchMode::Regex => rule.pattern.clone(),
+            };
+
+            rules.push(CompiledRule {
+                regex: regex::Regex::new(&pattern)?,
+                action: rule.action,
+                scope: rule.scope,
+            });
+        }
+
+        Ok(Self { rules })
+    }
+
+    pub fn check(&self, text: &str, scope: FilterScope) -> Verdict {
+        let folded = Folded::new(text);
+        let mut masked = vec![false; text.chars().count()];
+        let mut shadow_drop = false;
+
+        for rule in &self.rules {
+            if rule.scope != FilterScope::All && rule.scope != scope {
+                continue;
+            }
+
+            for m in rule.regex.find_iter(&folded.text) {
+                match rule.action {
+                    FilterAction::Reject => return Verdict::Reject,
+                    FilterAction::ShadowDrop => shadow_drop = true,
+                    FilterAction::Mask => {
+                        let mut originals = folded
+                            .offsets
+                            .iter()
+                            .filter(|(offset, _)| (m.start()..m.end()).contains(offset))
+                            .map(|(_, original)| *original);
+                        // the whole span, characters that folded away in the middle included
+                        let Some(first) = originals.next() else {
+                            continue;
+                        };
+                        let last = originals.next_back().unwrap_or(first);
+                        masked[first..=last].fill(true);
+                    }
+                }
+            }
+        }
+
+        if shadow_drop {
+            return Verdict::ShadowDrop;
+        }
+
+        Verdict::Pass(
+            text.chars()
+                .zip(masked)
+                .map(|(c, masked)| if masked && !c.is_whitespace() { '*' } else { c })
+                .collect(),
+        )
+    }
+}
+
+/// Text squashed down to what it "looks like": compatibility forms and accents are removed,
+/// everything is lowercase and leetspeak is turned back into letters. Keeps track of which
+/// original character every folded character came from so matches can be masked.
+struct Folded {
+    text: String,
+    offsets: Vec<(usize, usize)>, // (byte offset in `text`, char index in the original)
+}
+
+impl Folded {
+    fn new(original: &str) -> Self {
+        let mut text = String::with_capacity(original.len());
+        let mut offsets = Vec::with_capacity(original.len());
+
+        for (index, c) in original.chars().enumerate() {
+            for c in std::iter::once(c).nfkd().filter(|c| !is_combining_mark(*c)) {
+                for c in c.to_lowercase() {
+                    offsets.push((text.len(), index));
+                    text.push(unleet(c));
+                }
+            }
+        }
+
+        Self { text, offsets }
+    }
+}
+
+fn unleet(c: char) -> char {
+    match c {
+        '0' => 'o',
+        '1' | '!' => 'i',
+        '3' => 'e',
+        '4' | '@' => 'a',
+        '5' | '$' => 's',
+        '7' | '+' => 't',
+        '8' => 'b',
+        '9' => 'g',
+        '|' => 'l',
+        c => c,
+    }
+}
+
+/// The active filter, swapped out whenever the word list on disk changes.
+#[derive(Debug)]
+pub struct ContentFilter {
+    path: PathBuf,
+    current: RwLock<Arc<Filter>>,
+    modified: Mutex<Option<SystemTime>>,
+}
+
+impl ContentFilter {
+    pub fn new(settings: &FilterSettings) -> Self {
+        let filter = Self {
+            path: settings.word_list.clone(),
+            current: RwLock::new(Arc::new(Filter::default())),
+            modified: Mutex::new(None),
+        };
+
+        if let Err(e) = filter.reload() {
+            tracing::error!("failed to load word list, using the built-in one: {e:?}");
+            let builtin =
+                Filter::compile(&WordList::default()).expect("built-in word list is valid");
+            *filter.current.write().unwrap() = Arc::new(builtin);
+        }
+
+        filter
+    }
+
+    pub fn reload(&self) -> anyhow::Result<()> {
+        let list = if self.path.exists() {
+            WordList::load(&self.path)?
+        } else {
+            tracing::debug!("no word list at {:?}, using the built-in one", self.path);
+            WordList::default()
+        };
+
+        let filter = Filter::compile(&list)?;
+        tracing::info!("loaded {} filter rules", filter.rules.len());
+
+        *self.modified.lock().unwrap() = modified_at(&self.path);
+        *self.current.write().unwrap() = Arc::new(filter);
+        Ok(())
+    }
+
+    fn is_stale(&self) -> bool {
+        *self.modified.lock().unwrap() != modified_at(&self.path)
+    }
+
+    pub fn check_name(&self, name: &str) -> Verdict {
+        self.current.read().unwrap().check(name, FilterScope::Names)
+    }
+
+    pub fn check_message(&self, message: &str) -> Verdict {
+        self.current
+            .read()
+            .unwrap()
+            .check(message, FilterScope::Messages)
+    }
+}
+
+fn modified_at(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+/// Polls the word list for changes, a broken list keeps the previous one active.
+pub async fn watch(global: Arc<GlobalState>) {
+    let mut interval = tokio::time::interval(Duration::from_secs(
+        global.settings.filter.reload_interval.max(1),
+    ));
+
+    loop {
+        interval.tick().await;
+        if !global.filter.is_stale() {
+            continue;
+        }
+
+        tracing::info!("word list changed, reloading");
+        if let Err(e) = global.filter.reload() {
+            tracing::error!("failed to reload word list, keeping the previous one: {e:?}");
+            // don't retry the same broken file every tick
+            *global.filter.modified.lock().unwrap() = modified_at(&global.filter.path);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn filter(rules: &[(&str, MatchMode, FilterAction)]) -> Filter {
+        let rules = rules
+            .iter()
+            .map(|(pattern, mode, action)| FilterRule {
+                pattern: pattern.to_string(),
+                mode: *mode,
+                action: *action,
+                scope: FilterScope::All,
+            })
+            .collect();
+        Filter::compile(&WordList { rules }).unwrap()
+    }
+
+    fn pass(text: &str) -> Verdict {
+        Verdict::Pass(text.to_string())
+    }
+
+    #[test]
+    fn substrings_match_inside_words() {
+        let filter = filter(&[("bad", MatchMode::Substring, FilterAction::Mask)]);
+        assert_eq!(
+            filter.check("so baddie", FilterScope::Messages),
+            pass("so ***die")
+        );
+        assert_eq!(filter.check("good", FilterScope::Messages), pass("good"));
+    }
+
+    #[test]
+    fn words_only_match_on_their_own() {
+        let filter = filter(&[("bad", MatchMode::Word, FilterAction::Mask)]);
+        assert_eq!(
+            filter.check("bad day", FilterScope::Messages),
+            pass("*** day")
+        );
+        assert_eq!(
+            filter.check("baddie", FilterScope::Messages),
+            pass("baddie")
+        );
+    }
+
+    #[test]
+    fn regexes_match_the_folded_text() {
+        let filter = filter(&[(r"fr[e]+d", MatchMode::Regex, FilterAction::Mask)]);
+        assert_eq!(
+            filter.check("hi FR33ED", FilterScope::Messages),
+            pass("hi ******")
+        );
+    }
+
+    #[test]
+    fn folding_catches_accents_case_and_leetspeak() {
+        let filter = filter(&[("bad", MatchMode::Substring, FilterAction::Reject)]);
+        for text in ["BAD", "bäd", "b4d", "ｂａｄ", "b\u{0301}ad"] {
+            assert_eq!(
+                filter.check(text, FilterScope::Messages),
+                Verdict::Reject,
+                "{text}"
+            );
+        }
+    }
+
+    #[test]
+    fn masking_follows_folding_that_changes_length() {
+        // the ligature is one character that folds into two, the accent is one that folds away
+        let filter = filter(&[("fish", MatchMode::Substring, FilterAction::Mask)]);
+        assert_eq!(
+            filter.check("go ﬁsh now", FilterScope::Messages),
+            pass("go *** now")
+        );
+        assert_eq!(
+            filter.check("a fi\u{0301}sh!", FilterScope::Messages),
+            pass("a *****!")
+        );
+    }
+
+    #[test]
+    fn shadow_drops_win_over_masks() {
+        let filter = filter(&[
+            ("bad", MatchMode::Substring, FilterAction::Mask),
+            ("spam", MatchMode::Word, FilterAction::ShadowDrop),
+        ]);
+        assert_eq!(
+            filter.check("bad spam", FilterScope::Messages),
+            Verdict::ShadowDrop
+        );
+        assert_eq!(filter.check("bad", FilterScope::Messages), pass("***"));
+    }
+
+    #[test]
+    fn rejects_win_over_everything() {
+        let filter = filter(&[
+            ("spam", MatchMode::Word, FilterAction::ShadowDrop),
+            ("nope", MatchMode::Word, FilterAction::Reject),
+        ]);
+        assert_eq!(
+            filter.check("spam nope", FilterScope::Messages),
+            Verdict::Reject
+        );
+    }
+
+    #[test]
+    fn rules_stay_in_their_scope() {
+        let filter = Filter::compile(&WordList::default()).unwrap();
+        assert_eq!(filter.check("Admin", FilterScope::Names), Verdict::Reject);
+        assert_eq!(
+            filter.check("ask an admin", FilterScope::Messages),
+            pass("ask an admin")
+        );
+    }
+}
@@ -1,28 +1,50 @@
-use std::sync::{Arc, atomic::AtomicUsize};
+use std::{
+    net::IpAddr,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicBool, AtomicUsize},
+    },
+    time::{Duration, Instant},
+};
 
-use dashmap::DashMap;
+use dashmap::{DashMap, mapref::entry::Entry};
+use tokio::sync::Notify;
+use uuid::Uuid;
 
 use crate::{
-    room::{Room, RoomId},
+    audit::AuditLog,
+    filter::ContentFilter,
+    lobby::Lobby,
+    metrics::Metrics,
+    room::{Invite, Room, RoomId},
+    room_code,
+    session::{Ban, BanTarget, Resumable, Session, SessionEvent, SessionGuard},
     settings,
 };
 
+// rooms at least this full (in percent) are left for people who asked for them by name
+const QUICK_JOIN_NEAR_FULL: usize = 75;
+// longer mutes are clamped, a moderator asking for more than a year means forever anyway
+pub const MUTE_MAX_DURATION: Duration = Duration::from_secs(365 * 24 * 60 * 60);
+
 #[derive(Debug)]
-pub struct ActiveConnectionGuard(Arc<AtomicUsize>);
+pub struct ActiveConnectionGuard(Arc<AtomicUsize>, Arc<Notify>);
 
 impl Drop for ActiveConnectionGuard {
     fn drop(&mut self) {
         tracing::debug!("decrementing global active connections");
         self.0.fetch_sub(1, std::sync::atomic::Ordering::Relaxed);
+        self.1.notify_one();
     }
 }
 
 impl ActiveConnectionGuard {
-    pub fn new(active_connections: &Arc<AtomicUsize>) -> Self {
+    pub fn new(active_connections: &Arc<AtomicUsize>, changed: Arc<Notify>) -> Self {
         tracing::debug!("incrementing global active connections");
         active_connections.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+        changed.notify_one();
 
-        Self(active_connections.clone())
+        Self(active_connections.clone(), changed)
     }
 }
 
@@ -30,6 +52,19 @@ impl ActiveConnectionGuard {
 pub struct GlobalState {
     active_connections: Arc<AtomicUsize>,
     rooms: Arc<DashMap<RoomId, Room>>,
+    codes: DashMap<String, RoomId>, // normalized word codes
+    invites: DashMap<String, Invite>,
+    overflow: Mutex<()>, // held while picking or creating an overflow instance
+    sessions: Arc<DashMap<Uuid, Arc<Session>>>,
+    resumable: Arc<DashMap<String, Resumable>>, // by resume token
+    bans: DashMap<BanTarget, Ban>,
+    mutes: DashMap<IpAddr, Instant>, // until when, by address so reconnecting doesn't shake them
+    pub filter: ContentFilter,
+    pub metrics: Metrics,
+    pub audit: AuditLog,
+    pub lobby: Lobby,
+    started_at: Instant,
+    shutting_down: AtomicBool,
     pub settings: settings::Settings,
 }
 
@@ -37,14 +72,35 @@ impl GlobalState {
     pub fn new(settings: settings::Settings) -> Self {
         tracing::debug!("creating global state");
 
+        let lobby = Lobby::default();
         let rooms = Arc::new(DashMap::new());
-        for (id, room) in Room::default_public() {
-            rooms.insert(id, room);
+        for public_room in &settings.public_rooms {
+            let mut room = Room::new_public(public_room);
+            room.set_change_notifier(lobby.change_notifier());
+            if rooms.insert(room.id, room).is_some() {
+                tracing::warn!(
+                    "public room id {} is used more than once, only the last one is kept",
+                    public_room.id
+                );
+            }
         }
 
         Self {
             active_connections: Arc::new(AtomicUsize::new(0)),
             rooms,
+            codes: DashMap::new(),
+            invites: DashMap::new(),
+            overflow: Mutex::new(()),
+            sessions: Arc::new(DashMap::new()),
+            resumable: Arc::new(DashMap::new()),
+            bans: DashMap::new(),
+            mutes: DashMap::new(),
+            filter: ContentFilter::new(&settings.filter),
+            metrics: Metrics::default(),
+            audit: AuditLog::default(),
+            lobby,
+            started_at: Instant::now(),
+            shutting_down: AtomicBool::new(false),
             settings,
         }
     }
@@ -66,8 +122,22 @@ impl GlobalState {
             .load(std::sync::atomic::Ordering::Relaxed)
     }
 
+    pub fn uptime(&self) -> Duration {
+        self.started_at.elapsed()
+    }
+
+    pub fn begin_shutdown(&self) {
+        self.shutting_down
+            .store(true, std::sync::atomic::Ordering::Relaxed);
+    }
+
+    pub fn is_shutting_down(&self) -> bool {
+        self.shutting_down
+            .load(std::sync::atomic::Ordering::Relaxed)
+    }
+
     pub fn active_connection_guard(&self) -> ActiveConnectionGuard {
-        ActiveConnectionGuard::new(&self.active_connections)
+        ActiveConnectionGuard::new(&self.active_connections, self.lobby.change_notifier())
     }
 
     pub fn get_rooms(&self) -> Vec<Room> {
@@ -77,8 +147,8 @@ impl GlobalState {
             .map(|r| r.value().clone())
             .filter(|r| r.is_public)
             .collect();
-        // index is always some for public DEFAULT rooms
-        rooms.sort_by(|a, b| a.index.unwrap().cmp(&b.index.unwrap()));
+        // index is always some for public DEFAULT rooms, overflow instances go right after their parent
+        rooms.sort_by_key(|r| (r.index.unwrap(), r.instance));
         rooms
     }
 
@@ -86,11 +156,454 @@ impl GlobalState {
         self.rooms.get(&id).map(|v| v.value().clone())
     }
 
-    pub fn insert_room(&self, room: Room) -> Room {
-        let id = room.id;
-        match self.rooms.insert(id, room) {
-            Some(_) => panic!("room id shouldn't be collide with existing one"),
-            None => self.rooms.get(&id).unwrap().value().clone(),
+    /// Looks a room up by whatever someone typed in, its id or its word code. Every member gets to
+    /// see a private room's id, so that only gets people who've been inside back in.
+    pub fn find_room(&self, code: &str, session: Uuid) -> Option<Room> {
+        if let Some(room) = self
+            .find_room_by_id(code)
+            .filter(|r| r.admits_by_id(session))
+        {
+            return Some(room);
+        }
+
+        let id = *self.codes.get(&room_code::normalize(code))?;
+        self.get_room(id)
+    }
+
+    /// Any room by its id, no matter who's asking.
+    pub fn find_room_by_id(&self, code: &str) -> Option<Room> {
+        room_code::parse_id(code).and_then(|id| self.get_room(id.into()))
+    }
+
+    pub fn get_all_rooms(&self) -> Vec<Room> {
+        self.rooms.iter().map(|r| r.value().clone()).collect()
+    }
+
+    pub fn remove_room(&self, id: RoomId) -> Option<Room> {
+        let room = self.rooms.remove(&id).map(|(_, room)| room);
+        if let Some(code) = room.as_ref().and_then(|r| r.code()) {
+            self.codes.remove(&room_code::normalize(&code));
+        }
+        self.invites.retain(|_, invite| invite.room != id);
+        self.lobby.change_notifier().notify_one();
+        room
+    }
+
+    /// Stores a new room under a fresh id, rolling another one if it's already taken.
+    pub fn insert_room(&self, make: impl Fn(RoomId) -> Room) -> Room {
+        loop {
+            let mut room = make(RoomId::new());
+            let Entry::Vacant(entry) = self.rooms.entry(room.id) else {
+                tracing::warn!("room id {} is already taken, rolling again", room.id.id());
+                continue;
+            };
+
+            room.set_change_notifier(self.lobby.change_notifier());
+            if !room.is_public && self.settings.rooms.word_codes {
+                room.set_code(Some(self.allocate_code(room.id)));
+            }
+            return entry.insert(room).value().clone();
+        }
+    }
+
+    pub fn add_invite(&self, invite: Invite) {
+        self.invites.retain(|_, invite| !invite.is_expired());
+        self.invites.insert(invite.token.clone(), invite);
+    }
+
+    /// Takes one use of an invite if it's still good, and the room it leads to. The use has to be
+    /// given back with `release_invite` if getting in falls through.
+    pub fn claim_invite(&self, token: &str) -> Option<Room> {
+        let token = room_code::normalize(token);
+        let room = {
+            let mut invite = self.invites.get_mut(&token)?;
+            if invite.is_valid() {
+                invite.uses += 1;
+                Some(invite.room)
+            } else {
+                None
+            }
+        };
+
+        let Some(room) = room else {
+            self.invites
+                .remove_if(&token, |_, invite| invite.is_expired());
+            return None;
+        };
+        self.get_room(room)
+    }
+
+    pub fn release_invite(&self, token: &str) {
+        let token = room_code::normalize(token);
+        if let Some(mut invite) = self.invites.get_mut(&token) {
+            invite.uses = invite.uses.saturating_sub(1);
+        }
+    }
+
+    /// Revokes one of the room's invites, or all of them if no token is given. Revoking everything
+    /// also swaps out the room's word code, returned so the owner can hand the new one out, and
+    /// stops anyone who isn't inside right now from getting back in by id.
+    pub fn revoke_invites(&self, room: &Room, token: Option<&str>) -> (usize, Option<String>) {
+        let token = token.map(room_code::normalize);
+        let before = self.invites.len();
+        self.invites.retain(|t, invite| {
+            invite.room != room.id || token.as_ref().is_some_and(|token| token != t)
+        });
+        let revoked = before - self.invites.len();
+
+        if token.is_none() {
+            room.forget_former_members();
+        }
+        let code = match (token, room.code()) {
+            (None, Some(old)) => {
+                let code = self.allocate_code(room.id);
+                room.set_code(Some(code.clone()));
+                self.codes.remove(&room_code::normalize(&old));
+                Some(code)
+            }
+            _ => None,
+        };
+        (revoked, code)
+    }
+
+    fn allocate_code(&self, id: RoomId) -> String {
+        loop {
+            let code = room_code::generate();
+            if let Entry::Vacant(entry) = self.codes.entry(room_code::normalize(&code)) {
+                entry.insert(id);
+                return code;
+            }
+        }
+    }
+
+    /// A public room and all of its overflow instances, in instance order.
+    pub fn get_instances(&self, id: RoomId) -> Vec<Room> {
+        let mut rooms: Vec<_> = self
+            .rooms
+            .iter()
+            .filter(|r| r.id == id || r.parent == Some(id))
+            .map(|r| r.value().clone())
+            .collect();
+        rooms.sort_by_key(|r| r.instance);
+        rooms
+    }
+
+    /// Picks the fullest instance of a public room that still has space, so people end up
+    /// together, and opens a new overflow instance if they're all full.
+    pub fn best_instance(&self, id: RoomId) -> Option<Room> {
+        let room = self.get_room(id).filter(|r| r.is_public)?;
+        let parent = room.parent.unwrap_or(room.id);
+
+        let _lock = self.overflow.lock().unwrap();
+        let instances = self.get_instances(parent);
+        let best = instances
+            .iter()
+            .filter(|r| !r.is_locked() && r.current_connections() < r.max_connections)
+            .min_by_key(|r| (std::cmp::Reverse(r.current_connections()), r.instance));
+        if let Some(best) = best {
+            return Some(best.clone());
+        }
+
+        // reuse the lowest free number so instances don't count up forever
+        let parent = instances.iter().find(|r| r.id == parent)?;
+        let instance = (2..)
+            .find(|n| instances.iter().all(|r| r.instance != *n))
+            .unwrap();
+        tracing::debug!(
+            "opening instance {instance} of public room {}",
+            parent.id.id()
+        );
+        Some(self.insert_room(|id| Room::new_overflow(id, parent, instance)))
+    }
+
+    /// Public rooms worth quick joining, best first. Rooms with people that aren't close to full
+    /// come first, then empty ones, then nearly full ones, and the room the user just left last.
+    pub fn quick_join_candidates(&self, left: Option<RoomId>) -> Vec<Room> {
+        let mut rooms: Vec<_> = self
+            .get_rooms()
+            .into_iter()
+            .filter(|r| !r.is_locked() && r.current_connections() < r.max_connections)
+            .collect();
+
+        rooms.sort_by_key(|r| {
+            let fill = r.current_connections() * 100 / r.max_connections.max(1);
+            let tier = if Some(r.id) == left {
+                3
+            } else if fill >= QUICK_JOIN_NEAR_FULL {
+                2
+            } else if r.current_connections() == 0 {
+                1
+            } else {
+                0
+            };
+            // busier is better while there's room, emptier is better once it's getting tight
+            let fill = if tier == 0 { 100 - fill } else { fill };
+            (tier, fill, r.index, r.instance)
+        });
+        rooms
+    }
+
+    /// Merges empty overflow instances away, their members have all moved on.
+    pub fn prune_overflow(&self) {
+        let _lock = self.overflow.lock().unwrap();
+        let before = self.rooms.len();
+        self.rooms.retain(|_, r| {
+            r.parent.is_none() || r.current_connections() > 0 || r.current_spectators() > 0
+        });
+
+        if self.rooms.len() != before {
+            tracing::debug!(
+                "merged away {} empty overflow instances",
+                before - self.rooms.len()
+            );
+            self.lobby.change_notifier().notify_one();
         }
     }
+
+    pub fn session_guard(&self, session: &Arc<Session>) -> SessionGuard {
+        SessionGuard::new(session, &self.sessions, &self.resumable)
+    }
+
+    /// Hands back what a dropped session left behind, once. Nothing if it's too late, the session
+    /// is somehow still connected or it got banned.
+    pub fn resume(&self, token: &str) -> Option<Resumable> {
+        let (_, resumed) = self.resumable.remove(token)?;
+        if resumed.is_expired()
+            || self.sessions.contains_key(&resumed.id)
+            || self.is_session_banned(resumed.id)
+        {
+            return None;
+        }
+        Some(resumed)
+    }
+
+    pub fn get_session(&self, id: Uuid) -> Option<Arc<Session>> {
+        self.sessions.get(&id).map(|v| v.value().clone())
+    }
+
+    pub fn get_sessions(&self) -> Vec<Arc<Session>> {
+        self.sessions.iter().map(|s| s.value().clone()).collect()
+    }
+
+    /// Every connected session a ban applies to.
+    fn get_banned_sessions(&self, target: &BanTarget) -> Vec<Arc<Session>> {
+        self.sessions
+            .iter()
+            .filter(|s| match target {
+                BanTarget::Session(id) => s.id == *id,
+                BanTarget::Ip(ip) => s.ip == *ip,
+            })
+            .map(|s| s.value().clone())
+            .collect()
+    }
+
+    /// Records the ban and kicks out everyone it applies to. Session bans pick up the session's
+    /// address here if it's still connected.
+    pub fn ban(&self, mut ban: Ban) -> Ban {
+        if let BanTarget::Session(id) = ban.target
+            && ban.ip.is_none()
+        {
+            ban.ip = self.get_session(id).map(|s| s.ip);
+        }
+        tracing::info!("banning {:?} until {:?}", ban.target, ban.expires_at);
+
+        let duration = ban
+            .expires_at
+            .and_then(|expires_at| expires_at.duration_since(ban.created_at).ok());
+        for session in self.get_banned_sessions(&ban.target) {
+            session.notify(SessionEvent::Banned {
+                duration,
+                reason: ban.reason.clone(),
+            });
+        }
+
+        self.bans.insert(ban.target, ban.clone());
+        ban
+    }
+
+    /// Mutes everyone on the address, a zero duration unmutes. Returns how long it actually lasts.
+    pub fn mute(&self, ip: IpAddr, duration: Duration) -> Duration {
+        let duration = duration.min(MUTE_MAX_DURATION);
+        self.mutes.retain(|_, until| *until > Instant::now());
+        if duration.is_zero() {
+            self.mutes.remove(&ip);
+        } else {
+            self.mutes.insert(ip, Instant::now() + duration);
+        }
+        duration
+    }
+
+    pub fn is_muted(&self, ip: IpAddr) -> bool {
+        self.mutes
+            .remove_if(&ip, |_, until| *until <= Instant::now());
+        self.mutes.contains_key(&ip)
+    }
+
+    pub fn get_bans(&self) -> Vec<Ban> {
+        self.bans.retain(|_, ban| !ban.is_expired());
+        self.bans.iter().map(|b| b.value().clone()).collect()
+    }
+
+    pub fn unban(&self, target: &BanTarget) -> Option<Ban> {
+        self.bans.remove(target).map(|(_, ban)| ban)
+    }
+
+    /// Whether a new connection from this address gets turned away, by an address ban or by a
+    /// session ban that recorded it.
+    pub fn is_address_banned(&self, ip: IpAddr) -> bool {
+        self.bans.retain(|_, ban| !ban.is_expired());
+        self.bans.iter().any(|b| b.ip == Some(ip))
+    }
+
+    pub fn is_session_banned(&self, id: Uuid) -> bool {
+        self.bans
+            .get(&BanTarget::Session(id))
+            .is_some_and(|ban| !ban.is_expired())
+    }
+
+    pub fn check_moderator_secret(&self, secret: &str) -> bool {
+        self.settings
+            .moderation
+            .secret
+            .as_ref()
+            .is_some_and(|expected| secrets_match(expected, secret))
+    }
+
+    pub fn check_admin_token(&self, token: &str) -> bool {
+        self.settings
+            .admin
+            .token
+            .as_ref()
+            .is_some_and(|expected| secrets_match(expected, token))
+    }
+}
+
+// compare everything so the time taken doesn't leak how much of it matched
+fn secrets_match(expected: &str, given: &str) -> bool {
+    expected.len() == given.len()
+        && expected
+            .bytes()
+            .zip(given.bytes())
+            .fold(0, |acc, (a, b)| acc | (a ^ b))
+            == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn private_room(global: &GlobalState, owner: Uuid) -> Room {
+        global.insert_room(|id| Room::new_private(id, owner))
+    }
+
+    #[test]
+    fn private_rooms_are_not_found_by_id() {
+        let global = GlobalState::new(settings::Settings::default());
+        let owner = Uuid::new_v4();
+        let room = private_room(&global, owner);
+        let id = room.id.id().to_string();
+
+        assert!(global.find_room(&id, owner).is_some());
+        assert!(global.find_room(&id, Uuid::new_v4()).is_none());
+    }
+
+    #[test]
+    fn invite_uses_are_claimed_and_released() {
+        let global = GlobalState::new(settings::Settings::default());
+        let owner = Uuid::new_v4();
+        let room = private_room(&global, owner);
+        let invite = Invite::new(room.id, owner, Duration::from_secs(60), Some(1));
+        let token = invite.token.clone();
+        global.add_invite(invite);
+
+        assert!(global.claim_invite(&token).is_some());
+        assert!(global.claim_invite(&token).is_none());
+
+        global.release_invite(&token);
+        assert!(global.claim_invite(&token).is_some());
+    }
+
+    #[test]
+    fn revoking_everything_rotates_the_code() {
+        let mut settings = settings::Settings::default();
+        settings.rooms.word_codes = true;
+        let global = GlobalState::new(settings);
+        let owner = Uuid::new_v4();
+        let room = private_room(&global, owner);
+        let old = room.code().unwrap();
+        let invite = Invite::new(room.id, owner, Duration::from_secs(60), None);
+        let token = invite.token.clone();
+        global.add_invite(invite);
+
+        let (revoked, code) = global.revoke_invites(&room, None);
+        assert_eq!(revoked, 1);
+        let code = code.unwrap();
+        assert_ne!(code, old);
+        assert!(global.claim_invite(&token).is_none());
+        assert!(global.find_room(&old, Uuid::new_v4()).is_none());
+        assert!(global.find_room(&code, Uuid::new_v4()).is_some());
+    }
+
+    #[test]
+    fn session_bans_keep_the_address_out() {
+        let global = GlobalState::new(settings::Settings::default());
+        let id = Uuid::new_v4();
+        let ip: IpAddr = "192.0.2.1".parse().unwrap();
+        let persona = Arc::new(Mutex::new(crate::room::Persona::new(id)));
+        let (session, _rx) = Session::new(id, ip, persona);
+        let _guard = global.session_guard(&Arc::new(session));
+
+        let ban = global.ban(Ban::new(BanTarget::Session(id), None, None));
+        assert_eq!(ban.ip, Some(ip));
+        assert!(global.is_address_banned(ip));
+        assert!(!global.is_address_banned("192.0.2.2".parse().unwrap()));
+
+        global.unban(&BanTarget::Session(id));
+        assert!(!global.is_address_banned(ip));
+    }
+
+    fn dropped_session(global: &GlobalState, ignoring: Uuid) -> (Uuid, String) {
+        let id = Uuid::new_v4();
+        let persona = Arc::new(Mutex::new(crate::room::Persona::new(id)));
+        let (session, _rx) = Session::new(id, "192.0.2.1".parse().unwrap(), persona);
+        session.ignore(ignoring);
+        let token = session.resume_token.clone();
+        drop(global.session_guard(&Arc::new(session)));
+        (id, token)
+    }
+
+    #[test]
+    fn dropped_sessions_resume_once() {
+        let global = GlobalState::new(settings::Settings::default());
+        let troll = Uuid::new_v4();
+        let (id, token) = dropped_session(&global, troll);
+
+        assert!(global.resume("nonsense").is_none());
+        let resumed = global.resume(&token).unwrap();
+        assert_eq!(resumed.id, id);
+        assert!(resumed.ignored.contains(&troll));
+        assert!(global.resume(&token).is_none());
+    }
+
+    #[test]
+    fn banned_sessions_dont_resume() {
+        let global = GlobalState::new(settings::Settings::default());
+        let (id, token) = dropped_session(&global, Uuid::new_v4());
+
+        global.ban(Ban::new(BanTarget::Session(id), None, None));
+        assert!(global.resume(&token).is_none());
+    }
+
+    #[test]
+    fn mutes_go_by_address_and_are_clamped() {
+        let global = GlobalState::new(settings::Settings::default());
+        let ip: IpAddr = "192.0.2.1".parse().unwrap();
+
+        assert_eq!(global.mute(ip, Duration::MAX), MUTE_MAX_DURATION);
+        assert!(global.is_muted(ip));
+        assert!(!global.is_muted("192.0.2.2".parse().unwrap()));
+
+        global.mute(ip, Duration::ZERO);
+        assert!(!global.is_muted(ip));
+    }
 }
@@ -0,0 +1,322 @@
+use std::{
+    net::IpAddr,
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use axum::{
+    Json, Router,
+    extract::{Path, Request, State},
+    http::{StatusCode, header},
+    middleware::{self, Next},
+    response::{IntoResponse, Response},
+    routing::{get, post},
+};
+use uuid::Uuid;
+
+use crate::{
+    audit::{AuditAction, AuditEntry},
+    global::GlobalState,
+    room::{MessagePersona, Room, RoomMessage},
+    session::{Ban, BanTarget, Session, SessionEvent},
+};
+
+pub fn routes(global: &Arc<GlobalState>) -> Router<Arc<GlobalState>> {
+    Router::new()
+        .route("/rooms", get(list_rooms))
+        .route("/rooms/{id}", get(get_room).delete(close_room))
+        .route("/rooms/{id}/announce", post(announce_room))
+        .route("/announce", post(announce_all))
+        .route("/sessions", get(list_sessions))
+        .route("/sessions/{id}", get(get_session).delete(kick_session))
+        .route("/bans", get(list_bans).post(add_ban).delete(remove_ban))
+        .route("/audit", get(list_audit))
+        .layer(middleware::from_fn_with_state(global.clone(), authenticate))
+}
+
+async fn authenticate(
+    State(global): State<Arc<GlobalState>>,
+    req: Request,
+    next: Next,
+) -> Response {
+    let token = req
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "));
+
+    match token {
+        Some(token) if global.check_admin_token(token) => next.run(req).await,
+        _ => StatusCode::UNAUTHORIZED.into_response(),
+    }
+}
+
+fn unix_secs(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+#[derive(Debug, serde::Serialize)]
+struct RoomInfo {
+    id: mtid::Ttid,
+    name: String,
+    is_public: bool,
+    owner: Option<Uuid>,
+    locked: bool,
+    active_connections: usize,
+    max_connections: usize,
+    spectators: usize,
+    personas: Vec<MessagePersona>,
+}
+
+impl From<&Room> for RoomInfo {
+    fn from(room: &Room) -> Self {
+        Self {
+            id: room.id.id(),
+            name: room.name(),
+            is_public: room.is_public,
+            owner: room.owner(),
+            locked: room.is_locked(),
+            active_connections: room.current_connections(),
+            max_connections: room.max_connections,
+            spectators: room.current_spectators(),
+            personas: room
+                .personas
+                .lock()
+                .unwrap()
+                .iter()
+                .map(|p| MessagePersona::from_persona(&p.lock().unwrap()))
+                .collect(),
+        }
+    }
+}
+
+#[derive(Debug, serde::Serialize)]
+struct SessionInfo {
+    id: Uuid,
+    ip: IpAddr,
+    persona: MessagePersona,
+    rooms: Vec<mtid::Ttid>,
+    moderator: bool,
+    muted: bool,
+    connected_at: u64, // unix seconds
+}
+
+impl SessionInfo {
+    fn new(session: &Session, global: &GlobalState) -> Self {
+        // copied out first, rooms lock the same persona while looking for it
+        let persona = MessagePersona::from_persona(&session.persona.lock().unwrap());
+        let rooms = global
+            .get_all_rooms()
+            .iter()
+            .filter(|r| r.has_persona(persona.id))
+            .map(|r| r.id.id())
+            .collect();
+
+        Self {
+            id: session.id,
+            ip: session.ip,
+            persona,
+            rooms,
+            moderator: session.is_moderator(),
+            muted: global.is_muted(session.ip),
+            connected_at: unix_secs(session.connected_at),
+        }
+    }
+}
+
+#[derive(Debug, serde::Serialize)]
+struct BanInfo {
+    target: BanTarget,
+    ip: Option<IpAddr>,
+    reason: Option<String>,
+    created_at: u64,
+    expires_at: Option<u64>,
+}
+
+impl From<Ban> for BanInfo {
+    fn from(ban: Ban) -> Self {
+        Self {
+            target: ban.target,
+            ip: ban.ip,
+            reason: ban.reason,
+            created_at: unix_secs(ban.created_at),
+            expires_at: ban.expires_at.map(unix_secs),
+        }
+    }
+}
+
+#[derive(Debug, serde::Serialize)]
+struct AuditInfo {
+    at: u64,
+    actor: Uuid,
+    moderator: bool,
+    #[serde(flatten)]
+    action: AuditAction,
+}
+
+impl From<AuditEntry> for AuditInfo {
+    fn from(entry: AuditEntry) -> Self {
+        Self {
+            at: unix_secs(entry.at),
+            actor: entry.actor,
+            moderator: entry.moderator,
+            action: entry.action,
+        }
+    }
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct Announcement {
+    message: String,
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct Reason {
+    reason: Option<String>,
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct NewBan {
+    target: BanTarget,
+    duration: Option<u64>, // seconds, forever if not provided
+    reason: Option<String>,
+}
+
+async fn list_rooms(State(global): State<Arc<GlobalState>>) -> Json<Vec<RoomInfo>> {
+    let mut rooms: Vec<_> = global.get_all_rooms().iter().map(RoomInfo::from).collect();
+    rooms.sort_by(|a, b| b.is_public.cmp(&a.is_public).then(a.name.cmp(&b.name)));
+    Json(rooms)
+}
+
+async fn get_room(
+    State(global): State<Arc<GlobalState>>,
+    Path(id): Path<mtid::Ttid>,
+) -> Result<Json<RoomInfo>, StatusCode> {
+    let room = global.get_room(id.into()).ok_or(StatusCode::NOT_FOUND)?;
+    Ok(Json(RoomInfo::from(&room)))
+}
+
+/// Kicks everyone out. Private rooms are gone for good, public ones stay around empty.
+async fn close_room(
+    State(global): State<Arc<GlobalState>>,
+    Path(id): Path<mtid::Ttid>,
+) -> Result<StatusCode, StatusCode> {
+    let room = global.get_room(id.into()).ok_or(StatusCode::NOT_FOUND)?;
+    tracing::info!("admin closing room {}", room.id.id());
+
+    let _ = room.send(RoomMessage::system(
+        "This room is being closed".to_string(),
+        None,
+    ));
+    let members: Vec<_> = room
+        .personas
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|p| p.lock().unwrap().id)
+        .collect();
+    for session in members.into_iter().filter_map(|id| global.get_session(id)) {
+        session.notify(SessionEvent::Kicked {
+            room: Some(room.id),
+            reason: Some("room closed".to_string()),
+        });
+    }
+
+    if !room.is_public {
+        global.remove_room(room.id);
+    }
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn announce_room(
+    State(global): State<Arc<GlobalState>>,
+    Path(id): Path<mtid::Ttid>,
+    Json(announcement): Json<Announcement>,
+) -> Result<StatusCode, StatusCode> {
+    let room = global.get_room(id.into()).ok_or(StatusCode::NOT_FOUND)?;
+    let _ = room.send(RoomMessage::system(announcement.message, None));
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn announce_all(
+    State(global): State<Arc<GlobalState>>,
+    Json(announcement): Json<Announcement>,
+) -> StatusCode {
+    for room in global.get_all_rooms() {
+        let _ = room.send(RoomMessage::system(announcement.message.clone(), None));
+    }
+    StatusCode::NO_CONTENT
+}
+
+async fn list_sessions(State(global): State<Arc<GlobalState>>) -> Json<Vec<SessionInfo>> {
+    let mut sessions: Vec<_> = global
+        .get_sessions()
+        .iter()
+        .map(|s| SessionInfo::new(s, &global))
+        .collect();
+    sessions.sort_by_key(|s| s.connected_at);
+    Json(sessions)
+}
+
+async fn get_session(
+    State(global): State<Arc<GlobalState>>,
+    Path(id): Path<Uuid>,
+) -> Result<Json<SessionInfo>, StatusCode> {
+    let session = global.get_session(id).ok_or(StatusCode::NOT_FOUND)?;
+    Ok(Json(SessionInfo::new(&session, &global)))
+}
+
+async fn kick_session(
+    State(global): State<Arc<GlobalState>>,
+    Path(id): Path<Uuid>,
+    reason: Option<Json<Reason>>,
+) -> Result<StatusCode, StatusCode> {
+    let session = global.get_session(id).ok_or(StatusCode::NOT_FOUND)?;
+    tracing::info!("admin kicked session {id}");
+
+    session.notify(SessionEvent::Kicked {
+        room: None,
+        reason: reason.and_then(|Json(r)| r.reason),
+    });
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn list_bans(State(global): State<Arc<GlobalState>>) -> Json<Vec<BanInfo>> {
+    Json(global.get_bans().into_iter().map(BanInfo::from).collect())
+}
+
+async fn list_audit(State(global): State<Arc<GlobalState>>) -> Json<Vec<AuditInfo>> {
+    Json(
+        global
+            .audit
+            .entries()
+            .into_iter()
+            .map(AuditInfo::from)
+            .collect(),
+    )
+}
+
+async fn add_ban(
+    State(global): State<Arc<GlobalState>>,
+    Json(ban): Json<NewBan>,
+) -> impl IntoResponse {
+    let ban = Ban::new(
+        ban.target,
+        ban.duration.map(Duration::from_secs),
+        ban.reason,
+    );
+    let ban = global.ban(ban);
+    (StatusCode::CREATED, Json(BanInfo::from(ban)))
+}
+
+async fn remove_ban(
+    State(global): State<Arc<GlobalState>>,
+    Json(target): Json<BanTarget>,
+) -> Result<Json<BanInfo>, StatusCode> {
+    let ban = global.unban(&target).ok_or(StatusCode::NOT_FOUND)?;
+    tracing::info!("admin lifted ban on {:?}", ban.target);
+    Ok(Json(BanInfo::from(ban)))
+}
@@ -0,0 +1,198 @@
+use std::{
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+};
+
+use axum::{
+    extract::{ConnectInfo, FromRequestParts},
+    http::{HeaderMap, request::Parts},
+};
+
+use crate::{global::GlobalState, http::proxy::PeerAddr};
+
+/// The real address of the client. If the connection comes from one of the trusted proxies
+/// the forwarding headers are walked right to left until an untrusted hop is found.
+#[derive(Debug, Clone, Copy)]
+pub struct ClientIp(pub IpAddr);
+
+impl FromRequestParts<Arc<GlobalState>> for ClientIp {
+    type Rejection = <ConnectInfo<PeerAddr> as FromRequestParts<Arc<GlobalState>>>::Rejection;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &Arc<GlobalState>,
+    ) -> Result<Self, Self::Rejection> {
+        let ConnectInfo(PeerAddr(peer)) =
+            ConnectInfo::<PeerAddr>::from_request_parts(parts, state).await?;
+
+        Ok(Self(resolve(
+            peer.ip(),
+            &parts.headers,
+            &state.settings.http.trusted_proxies,
+        )))
+    }
+}
+
+fn resolve(peer: IpAddr, headers: &HeaderMap, trusted: &[IpAddr]) -> IpAddr {
+    if !trusted.contains(&peer) {
+        return peer;
+    }
+
+    // `Forwarded` is the standard one so it wins if a proxy sends both
+    let chain = match forwarded_chain(headers) {
+        Some(chain) => chain,
+        None => x_forwarded_for_chain(headers),
+    };
+
+    let mut client = peer;
+    for ip in chain.into_iter().rev() {
+        client = ip;
+        if !trusted.contains(&ip) {
+            break;
+        }
+    }
+
+    client
+}
+
+fn forwarded_chain(headers: &HeaderMap) -> Option<Vec<IpAddr>> {
+    let values: Vec<_> = headers
+        .get_all("forwarded")
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .collect();
+    if values.is_empty() {
+        return None;
+    }
+
+    let chain = values
+        .iter()
+        .flat_map(|v| v.split(','))
+        .filter_map(|element| {
+            element.split(';').find_map(|pair| {
+                let (key, value) = pair.trim().split_once('=')?;
+                key.eq_ignore_ascii_case("for")
+                    .then(|| parse_node(value.trim_matches('"')))
+                    .flatten()
+            })
+        })
+        .collect();
+
+    Some(chain)
+}
+
+fn x_forwarded_for_chain(headers: &HeaderMap) -> Vec<IpAddr> {
+    headers
+        .get_all("x-forwarded-for")
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .filter_map(|v| parse_node(v.trim()))
+        .collect()
+}
+
+// nodes can come as `1.2.3.4`, `1.2.3.4:5678`, `[::1]` or `[::1]:5678`
+fn parse_node(node: &str) -> Option<IpAddr> {
+    if let Ok(ip) = node.parse() {
+        return Some(ip);
+    }
+    if let Ok(addr) = node.parse::<SocketAddr>() {
+        return Some(addr.ip());
+    }
+
+    node.strip_prefix('[')
+        .and_then(|v| v.strip_suffix(']'))
+        .and_then(|v| v.parse().ok())
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::HeaderValue;
+
+    use super::*;
+
+    fn ip(v: &str) -> IpAddr {
+        v.parse().unwrap()
+    }
+
+    fn headers(pairs: &[(&'static str, &'static str)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (name, value) in pairs {
+            headers.append(*name, HeaderValue::from_static(value));
+        }
+        headers
+    }
+
+    #[test]
+    fn untrusted_peer_is_the_client() {
+        let headers = headers(&[("x-forwarded-for", "203.0.113.7")]);
+        assert_eq!(
+            resolve(ip("198.51.100.9"), &headers, &[]),
+            ip("198.51.100.9")
+        );
+    }
+
+    #[test]
+    fn stops_at_the_first_untrusted_hop() {
+        let trusted = [ip("10.0.0.1"), ip("10.0.0.2")];
+        // the client made up the first entry, the trusted proxies appended the rest
+        let headers = headers(&[("x-forwarded-for", "1.1.1.1, 203.0.113.7, 10.0.0.2")]);
+        assert_eq!(
+            resolve(ip("10.0.0.1"), &headers, &trusted),
+            ip("203.0.113.7")
+        );
+    }
+
+    #[test]
+    fn multiple_headers_are_one_chain() {
+        let trusted = [ip("10.0.0.1")];
+        let headers = headers(&[
+            ("x-forwarded-for", "1.1.1.1"),
+            ("x-forwarded-for", "203.0.113.7"),
+        ]);
+        assert_eq!(
+            resolve(ip("10.0.0.1"), &headers, &trusted),
+            ip("203.0.113.7")
+        );
+    }
+
+    #[test]
+    fn all_trusted_falls_back_to_the_leftmost() {
+        let trusted = [ip("10.0.0.1"), ip("10.0.0.2")];
+        let headers = headers(&[("x-forwarded-for", "10.0.0.2")]);
+        assert_eq!(resolve(ip("10.0.0.1"), &headers, &trusted), ip("10.0.0.2"));
+    }
+
+    #[test]
+    fn no_headers_means_the_proxy_itself() {
+        let trusted = [ip("10.0.0.1")];
+        assert_eq!(
+            resolve(ip("10.0.0.1"), &HeaderMap::new(), &trusted),
+            ip("10.0.0.1")
+        );
+    }
+
+    #[test]
+    fn forwarded_wins_over_x_forwarded_for() {
+        let trusted = [ip("10.0.0.1")];
+        let headers = headers(&[
+            ("x-forwarded-for", "198.51.100.1"),
+            (
+                "forwarded",
+                r#"for=192.0.2.60;proto=https, for="[2001:db8::1]:4711""#,
+            ),
+        ]);
+        assert_eq!(
+            resolve(ip("10.0.0.1"), &headers, &trusted),
+            ip("2001:db8::1")
+        );
+    }
+
+    #[test]
+    fn nodes_with_ports_and_brackets() {
+        assert_eq!(parse_node("192.0.2.1:8080"), Some(ip("192.0.2.1")));
+        assert_eq!(parse_node("[::1]"), Some(ip("::1")));
+        assert_eq!(parse_node("[::1]:8080"), Some(ip("::1")));
+        assert_eq!(parse_node("unknown"), None);
+    }
+}
@@ -0,0 +1,41 @@
+use std::sync::Arc;
+
+use axum::{Json, extract::State, http::StatusCode};
+
+use crate::{global::GlobalState, responses::PROTOCOL_VERSION};
+
+pub async fn healthz() -> &'static str {
+    "ok"
+}
+
+pub async fn readyz(State(global): State<Arc<GlobalState>>) -> (StatusCode, &'static str) {
+    if global.is_shutting_down() {
+        (StatusCode::SERVICE_UNAVAILABLE, "shutting down")
+    } else {
+        (StatusCode::OK, "ready")
+    }
+}
+
+#[derive(Debug, serde::Serialize)]
+pub struct ServerInfo {
+    version: &'static str,
+    protocol_version: u32,
+    uptime: u64, // seconds
+    population: usize,
+    public_rooms: usize,
+    private_rooms: usize,
+}
+
+pub async fn info(State(global): State<Arc<GlobalState>>) -> Json<ServerInfo> {
+    let rooms = global.get_all_rooms();
+    let public_rooms = rooms.iter().filter(|r| r.is_public).count();
+
+    Json(ServerInfo {
+        version: env!("CARGO_PKG_VERSION"),
+        protocol_version: PROTOCOL_VERSION,
+        uptime: global.uptime().as_secs(),
+        population: global.get_active_connections(),
+        public_rooms,
+        private_rooms: rooms.len() - public_rooms,
+    })
+}
@@ -0,0 +1,31 @@
+use std::sync::Arc;
+
+use axum::{Router, extract::State, http::header, response::IntoResponse, routing::get};
+
+use crate::global::GlobalState;
+
+async fn handler(State(global): State<Arc<GlobalState>>) -> impl IntoResponse {
+    (
+        [(header::CONTENT_TYPE, "text/plain; version=0.0.4")],
+        global.metrics.render(&global),
+    )
+}
+
+pub async fn run(global: Arc<GlobalState>) {
+    let bind = global.settings.metrics.bind;
+    let listener = match tokio::net::TcpListener::bind(bind).await {
+        Ok(listener) => listener,
+        Err(e) => {
+            tracing::error!("failed to bind the metrics server to {bind}: {e}");
+            return;
+        }
+    };
+    tracing::info!("Serving metrics on http://{bind}/metrics");
+
+    let routes = Router::new()
+        .route("/metrics", get(handler))
+        .with_state(global);
+    if let Err(e) = axum::serve(listener, routes).await {
+        tracing::error!("metrics server exited with error: {e}");
+    }
+}
@@ -6,14 +6,26 @@ use axum::{
 };
 use tokio::{net::TcpSocket, sync::oneshot};
 
-use crate::global::GlobalState;
+use crate::{
+    global::GlobalState,
+    http::proxy::{PeerAddr, WabbleListener},
+};
 
+mod admin;
+mod client_ip;
+mod health;
+mod metrics;
+mod proxy;
 mod socket;
 
 fn routes(global: &Arc<GlobalState>) -> Router {
     Router::new()
         .route("/", get(|| async { "Hello, World!" }))
+        .route("/healthz", get(health::healthz))
+        .route("/readyz", get(health::readyz))
+        .route("/info", get(health::info))
         .route("/socket", any(socket::handler))
+        .nest("/admin", admin::routes(global))
         .with_state(global.clone())
 }
 
@@ -29,13 +41,20 @@ pub async fn run(
     socket.set_nodelay(true)?;
 
     socket.bind(global.settings.http.bind)?;
-    let listener = socket.listen(1024)?;
+    let listener = WabbleListener::new(socket.listen(1024)?, global.settings.http.proxy_protocol)?;
+
+    if global.settings.metrics.enabled {
+        tokio::spawn(metrics::run(global.clone()));
+    }
 
     let routes = routes(&global);
-    axum::serve(listener, routes)
-        .with_graceful_shutdown(async move { _ = shutdown_signal.await })
-        .await
-        .expect("Failed to start the HTTP server");
+    axum::serve(
+        listener,
+        routes.into_make_service_with_connect_info::<PeerAddr>(),
+    )
+    .with_graceful_shutdown(async move { _ = shutdown_signal.await })
+    .await
+    .expect("Failed to start the HTTP server");
 
     Ok(())
 }
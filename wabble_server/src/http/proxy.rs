@@ -0,0 +1,258 @@
+use std::{
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    time::Duration,
+};
+
+use axum::{extract::connect_info::Connected, serve::IncomingStream};
+use tokio::{
+    io::AsyncReadExt,
+    net::{TcpListener, TcpStream},
+    sync::mpsc,
+};
+
+const V1_PREFIX: &[u8] = b"PROXY ";
+const V1_MAX_LENGTH: usize = 107; // as per the spec, including the CRLF
+const V2_SIGNATURE: &[u8] = b"\r\n\r\n\0\r\nQUIT\n";
+const HEADER_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The address of whoever is on the other end of the connection. When the PROXY protocol
+/// is enabled this is the address the proxy told us about, not the proxy itself.
+#[derive(Debug, Clone, Copy)]
+pub struct PeerAddr(pub SocketAddr);
+
+impl Connected<IncomingStream<'_, WabbleListener>> for PeerAddr {
+    fn connect_info(stream: IncomingStream<'_, WabbleListener>) -> Self {
+        Self(*stream.remote_addr())
+    }
+}
+
+#[derive(Debug)]
+pub enum WabbleListener {
+    Plain(TcpListener),
+    // headers are read in their own tasks so a slow client can't stall the accept loop
+    Proxied {
+        rx: mpsc::Receiver<(TcpStream, SocketAddr)>,
+        local_addr: SocketAddr,
+    },
+}
+
+impl WabbleListener {
+    pub fn new(listener: TcpListener, proxy_protocol: bool) -> io::Result<Self> {
+        if !proxy_protocol {
+            return Ok(Self::Plain(listener));
+        }
+
+        let local_addr = listener.local_addr()?;
+        let (tx, rx) = mpsc::channel(64);
+        tokio::spawn(accept_proxied(listener, tx));
+
+        Ok(Self::Proxied { rx, local_addr })
+    }
+}
+
+impl axum::serve::Listener for WabbleListener {
+    type Io = TcpStream;
+    type Addr = SocketAddr;
+
+    async fn accept(&mut self) -> (Self::Io, Self::Addr) {
+        match self {
+            Self::Plain(listener) => axum::serve::Listener::accept(listener).await,
+            Self::Proxied { rx, .. } => match rx.recv().await {
+                Some(conn) => conn,
+                // the accept loop only stops if the listener is gone for good
+                None => std::future::pending().await,
+            },
+        }
+    }
+
+    fn local_addr(&self) -> io::Result<Self::Addr> {
+        match self {
+            Self::Plain(listener) => listener.local_addr(),
+            Self::Proxied { local_addr, .. } => Ok(*local_addr),
+        }
+    }
+}
+
+async fn accept_proxied(listener: TcpListener, tx: mpsc::Sender<(TcpStream, SocketAddr)>) {
+    loop {
+        let (mut stream, peer) = match listener.accept().await {
+            Ok(conn) => conn,
+            Err(e) => {
+                tracing::error!("failed to accept connection: {e}");
+                tokio::time::sleep(Duration::from_secs(1)).await;
+                continue;
+            }
+        };
+
+        if tx.is_closed() {
+            break;
+        }
+
+        let tx = tx.clone();
+        tokio::spawn(async move {
+            match tokio::time::timeout(HEADER_TIMEOUT, read_header(&mut stream)).await {
+                Ok(Ok(addr)) => {
+                    let addr = addr.unwrap_or(peer);
+                    tracing::debug!("proxy {peer} forwarded connection from {addr}");
+                    _ = tx.send((stream, addr)).await;
+                }
+                Ok(Err(e)) => {
+                    tracing::debug!("dropping connection from {peer}, bad PROXY header: {e}");
+                }
+                Err(_) => {
+                    tracing::debug!("dropping connection from {peer}, PROXY header timed out");
+                }
+            }
+        });
+    }
+}
+
+fn invalid(msg: &'static str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// Consumes a PROXY protocol v1 or v2 header from the stream. Returns `None` for headers
+/// that don't carry an address (`UNKNOWN` and `LOCAL`, used for health checks).
+async fn read_header(stream: &mut TcpStream) -> io::Result<Option<SocketAddr>> {
+    let mut prefix = [0u8; 6];
+    stream.read_exact(&mut prefix).await?;
+
+    if prefix == V1_PREFIX {
+        let mut line = prefix.to_vec();
+        while !line.ends_with(b"\r\n") {
+            if line.len() >= V1_MAX_LENGTH {
+                return Err(invalid("v1 header is too long"));
+            }
+            line.push(stream.read_u8().await?);
+        }
+
+        let line = std::str::from_utf8(&line).map_err(|_| invalid("v1 header isn't ascii"))?;
+        return parse_v1(line);
+    }
+
+    if prefix == V2_SIGNATURE[..6] {
+        let mut rest = [0u8; 10];
+        stream.read_exact(&mut rest).await?;
+        if rest[..6] != V2_SIGNATURE[6..] {
+            return Err(invalid("bad v2 signature"));
+        }
+
+        let (version_command, family) = (rest[6], rest[7]);
+        let length = u16::from_be_bytes([rest[8], rest[9]]) as usize;
+        let mut payload = vec![0u8; length];
+        stream.read_exact(&mut payload).await?;
+
+        return parse_v2(version_command, family, &payload);
+    }
+
+    Err(invalid("missing PROXY header"))
+}
+
+fn parse_v1(line: &str) -> io::Result<Option<SocketAddr>> {
+    let mut parts = line.trim_end().split(' ').skip(1);
+
+    match parts.next() {
+        Some("UNKNOWN") => Ok(None),
+        Some("TCP4" | "TCP6") => {
+            let src: IpAddr = parts
+                .next()
+                .and_then(|v| v.parse().ok())
+                .ok_or(invalid("bad v1 source address"))?;
+            let _dst = parts.next();
+            let port: u16 = parts
+                .next()
+                .and_then(|v| v.parse().ok())
+                .ok_or(invalid("bad v1 source port"))?;
+
+            Ok(Some(SocketAddr::new(src, port)))
+        }
+        _ => Err(invalid("unknown v1 protocol")),
+    }
+}
+
+fn parse_v2(version_command: u8, family: u8, payload: &[u8]) -> io::Result<Option<SocketAddr>> {
+    if version_command >> 4 != 2 {
+        return Err(invalid("unsupported v2 version"));
+    }
+
+    match version_command & 0x0F {
+        0x0 => return Ok(None), // LOCAL
+        0x1 => {}               // PROXY
+        _ => return Err(invalid("unknown v2 command")),
+    }
+
+    // the high nibble is the address family, the low one the transport (stream or dgram)
+    match family >> 4 {
+        0x1 if payload.len() >= 12 => {
+            let ip = Ipv4Addr::new(payload[0], payload[1], payload[2], payload[3]);
+            let port = u16::from_be_bytes([payload[8], payload[9]]);
+            Ok(Some(SocketAddr::new(ip.into(), port)))
+        }
+        0x2 if payload.len() >= 36 => {
+            let mut octets = [0u8; 16];
+            octets.copy_from_slice(&payload[..16]);
+            let port = u16::from_be_bytes([payload[32], payload[33]]);
+            Ok(Some(SocketAddr::new(Ipv6Addr::from(octets).into(), port)))
+        }
+        0x0 | 0x3 => Ok(None), // UNSPEC and unix sockets don't give us anything useful
+        _ => Err(invalid("bad v2 address block")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn v1_tcp4() {
+        let addr = parse_v1("PROXY TCP4 192.0.2.1 198.51.100.1 56324 443\r\n").unwrap();
+        assert_eq!(addr, Some("192.0.2.1:56324".parse().unwrap()));
+    }
+
+    #[test]
+    fn v1_tcp6() {
+        let addr = parse_v1("PROXY TCP6 2001:db8::1 2001:db8::2 4000 443\r\n").unwrap();
+        assert_eq!(addr, Some("[2001:db8::1]:4000".parse().unwrap()));
+    }
+
+    #[test]
+    fn v1_unknown_has_no_address() {
+        assert_eq!(parse_v1("PROXY UNKNOWN\r\n").unwrap(), None);
+    }
+
+    #[test]
+    fn v1_rejects_garbage() {
+        assert!(parse_v1("PROXY UDP4 192.0.2.1 198.51.100.1 1 2\r\n").is_err());
+        assert!(parse_v1("PROXY TCP4 not-an-ip 198.51.100.1 1 2\r\n").is_err());
+        assert!(parse_v1("PROXY TCP4 192.0.2.1 198.51.100.1 70000 2\r\n").is_err());
+    }
+
+    #[test]
+    fn v2_ipv4() {
+        let payload = [192, 0, 2, 1, 198, 51, 100, 1, 0xDC, 0x04, 0x01, 0xBB];
+        let addr = parse_v2(0x21, 0x11, &payload).unwrap();
+        assert_eq!(addr, Some("192.0.2.1:56324".parse().unwrap()));
+    }
+
+    #[test]
+    fn v2_ipv6() {
+        let mut payload = [0u8; 36];
+        payload[..16].copy_from_slice(&"2001:db8::1".parse::<Ipv6Addr>().unwrap().octets());
+        payload[32..34].copy_from_slice(&4000u16.to_be_bytes());
+        let addr = parse_v2(0x21, 0x21, &payload).unwrap();
+        assert_eq!(addr, Some("[2001:db8::1]:4000".parse().unwrap()));
+    }
+
+    #[test]
+    fn v2_local_has_no_address() {
+        assert_eq!(parse_v2(0x20, 0x11, &[0; 12]).unwrap(), None);
+    }
+
+    #[test]
+    fn v2_rejects_garbage() {
+        assert!(parse_v2(0x11, 0x11, &[0; 12]).is_err()); // version 1
+        assert!(parse_v2(0x22, 0x11, &[0; 12]).is_err()); // unknown command
+        assert!(parse_v2(0x21, 0x11, &[0; 4]).is_err()); // short address block
+    }
+}
@@ -1,59 +1,152 @@
-use std::sync::{Arc, Mutex};
+use std::{
+    net::IpAddr,
+    ops::ControlFlow,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
 
 use axum::{
     extract::{
-        State, WebSocketUpgrade,
+        Query, State, WebSocketUpgrade,
         ws::{self, WebSocket},
     },
+    http::StatusCode,
     response::IntoResponse,
 };
 use tokio::sync::broadcast;
+use tracing::Instrument;
 use uuid::Uuid;
 
 use crate::{
+    audit::AuditAction,
+    commands::{self, Reply},
+    filter::Verdict,
     global::{ActiveConnectionGuard, GlobalState},
+    http::client_ip::ClientIp,
+    lobby::{LobbyRx, LobbyUpdate},
     responses::{self, Opcode, SocketComms, SocketResponse},
-    room::{MessagePersona, Persona, Room, RoomMessage, RoomSubscription},
+    room::{
+        INVITE_DEFAULT_EXPIRY, INVITE_MAX_EXPIRY, Invite, JoinError, KNOCK_TIMEOUT, MessageParent,
+        MessagePersona, Persona, Room, RoomEvent, RoomId, RoomMessage, RoomSubscription,
+        WaitlistTicket,
+    },
+    session::{
+        Ban, BanTarget, DirectMessagePolicy, Resumable, Session, SessionEvent, SessionGuard,
+        SessionRx,
+    },
 };
 
+// how long someone stays in a room's waitlist, and how often they hear back about it
+const WAITLIST_TIMEOUT: Duration = Duration::from_secs(300);
+const WAITLIST_UPDATE_INTERVAL: Duration = Duration::from_secs(5);
+// how long people get to take back what they said, moderators can delete anything anytime
+const UNSEND_WINDOW: Duration = Duration::from_secs(120);
+
+#[derive(Debug)]
+struct Waiting {
+    ticket: WaitlistTicket,
+    keep_others: bool,
+    deadline: tokio::time::Instant,
+    next_update: tokio::time::Instant,
+}
+
+#[derive(Debug)]
+struct Knocking {
+    room: Room,
+    keep_others: bool,
+    deadline: tokio::time::Instant,
+}
+
 #[derive(Debug)]
 struct SocketConnection {
     id: Uuid,
     socket: WebSocket,
     persona: Arc<Mutex<Persona>>,
+    session: Arc<Session>,
+    events: SessionRx,
     global: Arc<GlobalState>,
     _guard: ActiveConnectionGuard,
-    room_subscription: Option<RoomSubscription>,
+    _session_guard: SessionGuard,
+    rooms: Vec<RoomSubscription>, // in join order
+    last_room: Option<RoomId>,    // kept out of quick join picks
+    waiting: Option<Waiting>,
+    knocking: Option<Knocking>,
+    lobby_subscription: Option<LobbyRx>,
 }
 
 impl SocketConnection {
-    fn new(socket: WebSocket, guard: ActiveConnectionGuard, global: Arc<GlobalState>) -> Self {
-        let id = Uuid::new_v4();
+    fn new(
+        socket: WebSocket,
+        ip: IpAddr,
+        guard: ActiveConnectionGuard,
+        global: Arc<GlobalState>,
+        resumed: Option<Resumable>,
+    ) -> Self {
+        let id = resumed.as_ref().map_or_else(Uuid::new_v4, |r| r.id);
+        let persona = Arc::new(Mutex::new(Persona::new(id)));
+        let (session, events) = Session::new(id, ip, persona.clone());
+        if let Some(resumed) = resumed {
+            session.restore(resumed);
+        }
+        let session = Arc::new(session);
+
         Self {
             id,
             socket,
-            persona: Arc::new(Mutex::new(Persona::new(id))),
+            persona,
+            _session_guard: global.session_guard(&session),
+            session,
+            events,
             global,
             _guard: guard,
-            room_subscription: None,
+            rooms: Vec::new(),
+            last_room: None,
+            waiting: None,
+            knocking: None,
+            lobby_subscription: None,
         }
     }
 
     async fn send(&mut self, data: impl SocketResponse + serde::Serialize) {
+        let message: ws::Message = SocketComms::new(data).into();
+        self.global.metrics.bytes_out.add(message_len(&message));
+
         self.socket
-            .send(SocketComms::new(data).into())
+            .send(message)
             .await
             .expect("failed to send message to socket");
     }
 
-    async fn serve(&mut self) {
+    /// Pulls the request's data out, counting it as a parse error if it doesn't fit.
+    fn parse_data<T: serde::de::DeserializeOwned>(&self, data: SocketComms) -> Option<T> {
+        let Some(data) = data.data else {
+            tracing::debug!("gotten empty request data, ignoring");
+            return None;
+        };
+
+        match serde_json::from_value(data) {
+            Ok(data) => Some(data),
+            Err(e) => {
+                tracing::debug!("failed parsing request data: {e}");
+                self.global.metrics.parse_errors.inc();
+                None
+            }
+        }
+    }
+
+    async fn serve(&mut self, started: Instant) {
         // TODO: handle serde and other errors by using the typical enum pattern
         self.send(responses::Handshake {
             session_id: self.id,
+            resume_token: self.session.resume_token.clone(),
             active_connections: self.global.get_active_connections(),
             public_rooms: self.global.get_rooms().iter().map(|r| r.into()).collect(),
         })
         .await;
+        self.global
+            .metrics
+            .handshake_duration
+            .observe(started.elapsed());
 
         loop {
             tokio::select! {
@@ -61,45 +154,122 @@ impl SocketConnection {
                 res = self.socket.recv() => {
                     match res {
                         Some(Ok(ws::Message::Text(s))) => {
-                            let data = serde_json::from_str::<SocketComms>(s.as_str()).expect("failed parsing incoming data");
-                            tracing::debug!("received message: {:#?}", data);
-                            self.handle_message(data).await;
+                            self.global.metrics.bytes_in.add(s.len() as u64);
+                            match serde_json::from_str::<SocketComms>(s.as_str()) {
+                                Ok(data) => {
+                                    tracing::debug!("received message: {:#?}", data);
+                                    self.handle_message(data).await;
+                                }
+                                Err(e) => {
+                                    tracing::debug!("failed parsing incoming data: {e}");
+                                    self.global.metrics.parse_errors.inc();
+                                }
+                            }
+                        }
+                        Some(Ok(message)) => {
+                            self.global.metrics.bytes_in.add(message_len(&message));
                         }
-                        Some(Ok(_)) => {}
                         Some(Err(e)) => {
                             tracing::debug!("client disconnected abruptly: {e}");
-                            self.leave_room().await;
+                            self.leave_rooms().await;
                         },
                         None => {
                             tracing::debug!("client disconnected gracefully");
-                            self.leave_room().await;
+                            self.leave_rooms().await;
                             break;
                         },
                     }
                 }
-                msg = async {
-                    match &mut self.room_subscription {
-                        Some(rx) => rx.recv().await,
-                        None => std::future::pending().await, // never resolves
+                (room, msg) = async {
+                    if self.rooms.is_empty() {
+                        return std::future::pending().await; // never resolves
                     }
+                    let receivers = self
+                        .rooms
+                        .iter_mut()
+                        .map(|s| Box::pin(async move { (s.room.id, s.recv().await) }));
+                    futures_util::future::select_all(receivers).await.0
                 } => {
                     match msg {
-                        Ok(broadcast_msg) => {
+                        Ok(RoomEvent::Deleted { id }) => {
+                            self.send(responses::MessageDeleted { id, room: room.id() }).await;
+                        }
+                        Ok(RoomEvent::Reacted { id, stamp, persona, added, count }) => {
+                            self.send(responses::Reaction {
+                                room: room.id(),
+                                id,
+                                stamp,
+                                persona_id: persona,
+                                added,
+                                count,
+                            })
+                            .await;
+                        }
+                        Ok(RoomEvent::Message(broadcast_msg)) => {
                             tracing::debug!("broadcasting message to socket {}: {:?}", self.id, broadcast_msg);
                             // if broadcast_msg.persona.id == self.id {
                             //     tracing::debug!("skipping echo message to self for socket {}", self.id);
                             //     continue;
                             // }
+                            if self.session.is_ignoring(broadcast_msg.persona.id) {
+                                continue;
+                            }
 
-                            self.send(responses::EchoMessage::from(broadcast_msg)).await;
+                            self.send(responses::EchoMessage::from(broadcast_msg).in_room(room)).await;
                         }
                         Err(broadcast::error::RecvError::Lagged(skipped)) => {
                             tracing::warn!("socket {} lagged and skipped {} messages", self.id, skipped);
+                            self.global.metrics.broadcast_lag_events.inc();
+                            self.global.metrics.broadcast_lag_skipped.add(skipped);
                         }
                         Err(broadcast::error::RecvError::Closed) => {
                             tracing::debug!("room broadcast channel closed for socket {}", self.id);
-                            self.leave_room().await;
+                            self.leave_room(room).await;
+                        }
+                    }
+                }
+                update = async {
+                    match &mut self.lobby_subscription {
+                        Some(rx) => rx.recv().await,
+                        None => std::future::pending().await,
+                    }
+                } => {
+                    match update {
+                        Ok(update) => self.send_lobby_update(update).await,
+                        Err(broadcast::error::RecvError::Lagged(_)) => {
+                            tracing::debug!("socket {} lagged behind the lobby, resending everything", self.id);
+                            self.send_lobby_snapshot().await;
                         }
+                        Err(broadcast::error::RecvError::Closed) => {
+                            self.lobby_subscription = None;
+                        }
+                    }
+                }
+                _ = async {
+                    match &self.waiting {
+                        Some(waiting) => tokio::time::sleep_until(waiting.next_update).await,
+                        None => std::future::pending().await,
+                    }
+                } => self.update_waitlist().await,
+                _ = async {
+                    match &self.knocking {
+                        Some(knocking) => tokio::time::sleep_until(knocking.deadline).await,
+                        None => std::future::pending().await,
+                    }
+                } => {
+                    if let Some(knocking) = self.knocking.take() {
+                        tracing::debug!("nobody answered the knock on room {}", knocking.room.id.id());
+                        knocking.room.take_knock(self.id);
+                        self.send(responses::KnockStatus {
+                            id: knocking.room.id.id(),
+                            state: responses::KnockState::Expired,
+                        })
+                        .await;
+                    }
+                }
+                Some(event) = self.events.recv() => {
+                    if self.handle_event(event).await.is_break() {
+                        break;
                     }
                 }
 
@@ -110,128 +280,521 @@ impl SocketConnection {
     async fn handle_message(&mut self, data: SocketComms) {
         match data.opcode {
             Opcode::Persona => {
-                let Some(data) = data.data else {
-                    tracing::debug!("gotten empty request data, ignoring");
+                let Some(persona) = self.parse_data::<responses::Persona>(data) else {
                     return;
                 };
-
-                let persona: responses::Persona =
-                    serde_json::from_value(data).expect("failed parsing persona");
                 tracing::debug!("received new persona");
-                let current_persona = self
-                    .persona
-                    .try_lock()
-                    .expect("failed to lock persona")
-                    .clone();
-                *self.persona.try_lock().expect("failed to lock persona") =
-                    Persona::from_response(persona, current_persona);
+                let current_persona = self.persona.lock().unwrap().clone();
+                *self.persona.lock().unwrap() =
+                    Persona::from_response(persona, current_persona, &self.global.filter);
                 tracing::debug!("updated persona: {:#?}", self.persona);
 
                 tracing::debug!("checking for name collisions in socket's current room");
-                if let Some(ref room_subscription) = self.room_subscription {
-                    room_subscription
-                        .room
-                        .check_collisions(self.persona.clone());
+                for subscription in self.rooms.iter().filter(|s| !s.spectator) {
+                    subscription.room.check_collisions(self.persona.clone());
                 }
             }
             Opcode::JoinRoom => {
-                let Some(data) = data.data else {
-                    tracing::debug!("gotten empty request data, ignoring");
+                let Some(request) = self.parse_data::<responses::JoinRoom>(data) else {
                     return;
                 };
-                let room: responses::JoinRoom =
-                    serde_json::from_value(data).expect("failed parsing join room schema");
-                tracing::debug!("received join room: {:#?}", room);
-                self.leave_room().await;
+                tracing::debug!("received join room: {:#?}", request);
 
-                let room = self.global.get_room(room.id.into());
-                if let Some(room) = room {
-                    tracing::debug!("found the requested room");
-                    match room.subscribe(self.persona.clone()).await {
-                        Some(mut subscription) => {
-                            tracing::debug!(
-                                "subscribed to room successfully, sending system message"
-                            );
-                            let persona = self
-                                .persona
-                                .try_lock()
-                                .expect("failed to lock persona")
-                                .clone();
-                            _ = subscription.send_hello(&persona).await;
-
-                            if !room.is_public {
-                                _ = subscription.send_invite(room.id).await;
-                            }
+                let room = self.global.find_room(&request.id, self.id);
+                if let Some(room) = &room
+                    && self.subscription(Some(room.id.id())).is_some()
+                {
+                    tracing::debug!("already in room {}, ignoring", room.id.id());
+                    return;
+                }
+                let keep_others = request.keep_others;
 
-                            self.room_subscription = Some(subscription)
+                if request.spectate {
+                    match room.filter(|r| r.is_public) {
+                        Some(room) => match room.spectate(self.persona.clone()) {
+                            Ok(subscription) => {
+                                if let Err(e) = self.enter_open(subscription, keep_others).await {
+                                    tracing::debug!("cannot spectate room: {e:?}");
+                                }
+                            }
+                            Err(e) => tracing::debug!("cannot spectate room: {e:?}"),
+                        },
+                        None => tracing::debug!("only public rooms can be spectated"),
+                    }
+                } else if let Some(room) = room {
+                    tracing::debug!("found the requested room");
+                    match self.join(room.clone(), keep_others).await {
+                        Ok(()) => {}
+                        Err(JoinError::Full) if request.wait => {
+                            tracing::debug!("room is full, getting in line");
+                            self.wait_for(room, keep_others).await;
                         }
-                        None => {
-                            tracing::debug!("room is full, cannot join")
+                        Err(JoinError::Full) if room.is_public => {
+                            tracing::debug!("room is full, spilling into an overflow instance");
+                            self.join_any(room.id, keep_others).await;
                         }
+                        Err(e) => {
+                            tracing::debug!("cannot join room: {e:?}");
+                            self.send(responses::JoinFailed {
+                                id: request.id,
+                                reason: e.into(),
+                            })
+                            .await;
+                        }
+                    }
+                } else if let Some(room) = self.global.claim_invite(&request.id) {
+                    if self.subscription(Some(room.id.id())).is_some() {
+                        tracing::debug!("already in room {}, ignoring", room.id.id());
+                        self.global.release_invite(&request.id);
+                        return;
+                    }
+                    tracing::debug!("joining room {} with an invite", room.id.id());
+                    if let Err(e) = self.join_invited(room, keep_others).await {
+                        tracing::debug!("cannot join with invite: {e:?}");
+                        self.global.release_invite(&request.id);
+                        self.send(responses::JoinFailed {
+                            id: request.id,
+                            reason: e.into(),
+                        })
+                        .await;
                     }
+                } else if self.global.find_room_by_id(&request.id).is_some() {
+                    tracing::debug!("private room asked for by id by a stranger, refusing");
+                    self.send(responses::JoinFailed {
+                        id: request.id,
+                        reason: responses::JoinFailure::InviteRequired,
+                    })
+                    .await;
                 } else {
-                    let room = self.global.insert_room(Room::new_private());
+                    let room = self.global.insert_room(|id| Room::new_private(id, self.id));
                     tracing::debug!("created and joining new private room with id {:?}", room.id);
 
-                    match room.subscribe(self.persona.clone()).await {
-                        Some(mut subscription) => {
-                            tracing::debug!(
-                                "subscribed to room successfully, sending system message"
-                            );
-                            let persona = self
-                                .persona
-                                .try_lock()
-                                .expect("failed to lock persona")
-                                .clone();
-                            _ = subscription.send_hello(&persona).await;
-                            _ = subscription.send_invite(room.id).await;
-
-                            self.room_subscription = Some(subscription);
-                        }
-                        None => {
-                            tracing::debug!("room is full, cannot join")
+                    if let Err(e) = self.join(room.clone(), keep_others).await {
+                        tracing::debug!("cannot join freshly created room: {e:?}")
+                    }
+                    // the id is no way in for anyone else, so the creator gets something to share
+                    let code = room.code().unwrap_or_else(|| {
+                        let invite = Invite::new(room.id, self.id, INVITE_MAX_EXPIRY, None);
+                        let token = invite.token.clone();
+                        self.global.add_invite(invite);
+                        token
+                    });
+                    if let Some(subscription) = self.rooms.last_mut() {
+                        subscription.send_invite(&code).await;
+                    }
+                }
+            }
+            Opcode::JoinAny => {
+                let Some(room) = self.parse_data::<responses::JoinAny>(data) else {
+                    return;
+                };
+                tracing::debug!("received join any: {:#?}", room);
+                self.join_any(room.id.into(), room.keep_others).await;
+            }
+            Opcode::LeaveWaitlist => {
+                tracing::debug!("received leave waitlist");
+                if let Some(waiting) = self.waiting.take() {
+                    self.send(responses::WaitlistStatus {
+                        id: waiting.ticket.room.id.id(),
+                        state: responses::WaitlistState::Cancelled,
+                        position: None,
+                    })
+                    .await;
+                }
+            }
+            Opcode::Knock => {
+                let Some(knock) = self.parse_data::<responses::Knock>(data) else {
+                    return;
+                };
+
+                // knocking is how strangers ask into a private room, so the raw id is enough here
+                let Some(room) = self
+                    .global
+                    .find_room_by_id(&knock.id)
+                    .or_else(|| self.global.find_room(&knock.id, self.id))
+                else {
+                    tracing::debug!("knocked on a room that doesn't exist");
+                    return;
+                };
+                if self.subscription(Some(room.id.id())).is_some() {
+                    tracing::debug!("knocked on a room we're already in, ignoring");
+                    return;
+                }
+                if !room.is_locked() && room.admits_by_id(self.id) {
+                    tracing::debug!("room isn't locked and will have us, just joining it");
+                    if let Err(e) = self.join(room, knock.keep_others).await {
+                        tracing::debug!("cannot join room: {e:?}");
+                    }
+                    return;
+                }
+
+                if !room.knock(self.id) {
+                    self.send(responses::KnockStatus {
+                        id: room.id.id(),
+                        state: responses::KnockState::QueueFull,
+                    })
+                    .await;
+                    return;
+                }
+
+                let persona = MessagePersona::from_persona(&self.session.persona.lock().unwrap());
+                for gatekeeper in room
+                    .gatekeepers()
+                    .into_iter()
+                    .filter_map(|id| self.global.get_session(id))
+                {
+                    gatekeeper.notify(SessionEvent::Knocked {
+                        room: room.id,
+                        persona: persona.clone(),
+                    });
+                }
+
+                // knocking somewhere else gives up on the last knock
+                if let Some(previous) = self.knocking.take() {
+                    previous.room.take_knock(self.id);
+                }
+                self.send(responses::KnockStatus {
+                    id: room.id.id(),
+                    state: responses::KnockState::Pending,
+                })
+                .await;
+                self.knocking = Some(Knocking {
+                    room,
+                    keep_others: knock.keep_others,
+                    deadline: tokio::time::Instant::now() + KNOCK_TIMEOUT,
+                });
+            }
+            Opcode::AnswerKnock => {
+                let Some(answer) = self.parse_data::<responses::AnswerKnock>(data) else {
+                    return;
+                };
+
+                // whichever of our rooms they knocked on
+                let Some(room) = self
+                    .rooms
+                    .iter()
+                    .map(|s| &s.room)
+                    .find(|r| r.is_gatekeeper(self.id) && r.take_knock(answer.persona_id))
+                    .cloned()
+                else {
+                    tracing::debug!("no pending knock from {}, ignoring", answer.persona_id);
+                    return;
+                };
+
+                if let Some(knocker) = self.global.get_session(answer.persona_id) {
+                    knocker.notify(SessionEvent::KnockAnswered {
+                        room: room.id,
+                        approved: answer.approve,
+                    });
+                }
+            }
+            Opcode::QuickJoin => {
+                // older clients send no data at all
+                let keep_others = match data.data {
+                    Some(_) => {
+                        let Some(quick_join) = self.parse_data::<responses::QuickJoinRequest>(data)
+                        else {
+                            return;
+                        };
+                        quick_join.keep_others
+                    }
+                    None => false,
+                };
+                tracing::debug!("received quick join");
+                let mut joined = None;
+
+                for room in self.global.quick_join_candidates(self.last_room) {
+                    if self.subscription(Some(room.id.id())).is_some() {
+                        continue;
+                    }
+                    match self.join(room.clone(), keep_others).await {
+                        Ok(()) => {
+                            joined = Some(room.id);
+                            break;
                         }
+                        Err(e) => tracing::debug!("cannot quick join {}: {e:?}", room.id.id()),
                     }
                 }
+
+                // everything is full, open up more space
+                if joined.is_none()
+                    && let Some(room) = self.global.get_rooms().first()
+                {
+                    joined = self.join_any(room.id, keep_others).await;
+                }
+
+                let room = joined
+                    .and_then(|id| self.subscription(Some(id.id())))
+                    .map(|s| responses::PublicRoomInfo::from(&s.room));
+                self.send(responses::QuickJoin { room }).await;
             }
             Opcode::SendMessage => {
-                let Some(data) = data.data else {
-                    tracing::debug!("gotten empty request data, ignoring");
+                let Some(msg) = self.parse_data::<responses::SendMessage>(data) else {
                     return;
                 };
-                let msg: responses::SendMessage =
-                    serde_json::from_value(data).expect("failed parsing send message schema");
 
                 tracing::debug!("received send message: {:#?}", msg);
 
-                let Some(ref room) = self.room_subscription else {
-                    tracing::debug!("no room joined, ignoring request");
+                let Some(subscription) = self.subscription(msg.room) else {
+                    tracing::debug!("not in that room, ignoring request");
                     return;
                 };
+                let target = subscription.room.clone();
+                let room = target.id;
+                if subscription.spectator {
+                    tracing::debug!("spectators can't send messages, refusing");
+                    self.tell(
+                        Some(room),
+                        "You're spectating, so you can't send messages here",
+                    )
+                    .await;
+                    return;
+                }
 
-                let persona = self
-                    .persona
-                    .try_lock()
-                    .expect("failed to lock socket's persona");
+                let context = commands::Context {
+                    global: &self.global,
+                    session: &self.session,
+                    persona: &self.persona,
+                    room: &target,
+                    member_rooms: self
+                        .rooms
+                        .iter()
+                        .filter(|s| !s.spectator)
+                        .map(|s| s.room.clone())
+                        .collect(),
+                };
+                let text = match commands::run(&context, &msg.message) {
+                    Some(Reply::Private(text)) => {
+                        self.tell(Some(room), &text).await;
+                        return;
+                    }
+                    Some(Reply::Room(text)) => {
+                        let _ = target.send(RoomMessage::system(text, None));
+                        return;
+                    }
+                    Some(Reply::Say(text)) => text,
+                    // `//` is how you say something starting with a slash
+                    None => match msg.message.strip_prefix('/') {
+                        Some(rest) if rest.starts_with('/') => rest.to_string(),
+                        _ => msg.message,
+                    },
+                };
 
-                let mut message = msg.message;
-                if message.len() > 165 {
-                    message = message.chars().take(165).collect()
+                if self.global.is_muted(self.session.ip) {
+                    tracing::debug!("socket is muted, ignoring request");
+                    return;
+                }
+
+                let parent = msg.reply_to.map(|id| target.find_message(id));
+                let parent = match parent {
+                    Some(None) => {
+                        tracing::debug!("replying to a message that's not in history, refusing");
+                        self.tell(Some(room), "The message you replied to is gone")
+                            .await;
+                        return;
+                    }
+                    Some(Some(parent)) => Some(parent),
+                    None => None,
+                };
+                if msg.remix
+                    && !(msg.drawing.is_some()
+                        && parent.as_ref().is_some_and(|p| p.drawing.is_some()))
+                {
+                    tracing::debug!("remix without two drawings, refusing");
+                    self.tell(Some(room), "Only drawings can be remixed").await;
+                    return;
+                }
+
+                let Some((mut message, shadow_dropped)) = self.compose_message(text, msg.drawing)
+                else {
+                    return;
+                };
+                message.parent = parent.map(|p| Box::new(MessageParent::new(&p, msg.remix)));
+                message.mentions = target.resolve_mentions(&message.message);
+                if shadow_dropped {
+                    // only echo it back to the sender so they don't notice
+                    self.send(responses::EchoMessage::from(message).in_room(room))
+                        .await;
+                    return;
+                }
+
+                let has_drawing = message.drawing.is_some();
+                let mentioned: Vec<Uuid> = message
+                    .mentions
+                    .iter()
+                    .map(|m| m.id)
+                    .filter(|id| *id != self.id)
+                    .collect();
+                let Some(id) = target.send(message.clone()) else {
+                    return;
+                };
+                self.global.metrics.messages_relayed.inc();
+                if has_drawing {
+                    self.global.metrics.drawings_relayed.inc();
+                }
+
+                message.id = id;
+                for session in mentioned
+                    .into_iter()
+                    .filter_map(|id| self.global.get_session(id))
+                {
+                    session.notify(SessionEvent::Mentioned {
+                        room,
+                        message: message.clone(),
+                    });
+                }
+            }
+            Opcode::DirectMessage => {
+                let Some(dm) = self.parse_data::<responses::DirectMessage>(data) else {
+                    return;
+                };
+
+                if self.global.is_muted(self.session.ip) {
+                    tracing::debug!("socket is muted, ignoring request");
+                    return;
+                }
+
+                let Some(target) = self
+                    .global
+                    .get_session(dm.persona_id)
+                    .filter(|target| target.id != self.id && self.can_message(target))
+                else {
+                    // same answer whether they're gone or just don't want it
+                    tracing::debug!("can't direct message {}, refusing", dm.persona_id);
+                    self.tell(None, "Couldn't deliver your direct message")
+                        .await;
+                    return;
+                };
+
+                let Some((message, shadow_dropped)) = self.compose_message(dm.message, dm.drawing)
+                else {
+                    return;
+                };
+                if !shadow_dropped {
+                    target.notify(SessionEvent::DirectMessage {
+                        message: message.clone(),
+                    });
+                    self.global.metrics.direct_messages_relayed.inc();
+                }
+                self.send(responses::DirectMessage::new(target.id, message))
+                    .await;
+            }
+            Opcode::DirectMessagePolicy => {
+                let Some(request) = self.parse_data::<responses::DirectMessagePolicy>(data) else {
+                    return;
+                };
+
+                tracing::debug!("socket {} now takes dms from {:?}", self.id, request.policy);
+                self.session.set_dm_policy(request.policy);
+                self.send(request).await;
+            }
+            Opcode::Ignore => {
+                let Some(mut request) = self.parse_data::<responses::Ignore>(data) else {
+                    return;
+                };
+
+                if request.persona_id == self.id {
+                    tracing::debug!("socket {} tried ignoring itself", self.id);
+                    return;
+                }
+                if !self.session.ignore(request.persona_id) {
+                    tracing::debug!("socket {} ignores too many people already", self.id);
+                }
+                request.ignored = self.session.get_ignored();
+                self.send(request).await;
+            }
+            Opcode::Unignore => {
+                let Some(mut request) = self.parse_data::<responses::Unignore>(data) else {
+                    return;
+                };
+
+                self.session.unignore(request.persona_id);
+                request.ignored = self.session.get_ignored();
+                self.send(request).await;
+            }
+            Opcode::DeleteMessage => {
+                let Some(request) = self.parse_data::<responses::DeleteMessage>(data) else {
+                    return;
+                };
+
+                let moderator = self.session.is_moderator();
+                let room = match self.subscription(request.room) {
+                    Some(subscription) => Some(subscription.room.clone()),
+                    None if moderator => {
+                        request.room.and_then(|id| self.global.get_room(id.into()))
+                    }
+                    None => None,
+                };
+                let Some(room) = room else {
+                    tracing::debug!("not in that room, ignoring request");
+                    return;
+                };
+
+                let Some(message) = room.find_message(request.id) else {
+                    tracing::debug!("message {} isn't in history, ignoring", request.id);
+                    return;
+                };
+                let unsend =
+                    message.persona.id == self.id && message.sent_at.elapsed() <= UNSEND_WINDOW;
+                if !unsend && !moderator {
+                    tracing::debug!("socket {} can't delete message {}", self.id, request.id);
+                    self.tell(
+                        Some(room.id),
+                        "You can only take back your own messages, and only for a little while",
+                    )
+                    .await;
+                    return;
+                }
+
+                // someone else might have just deleted it
+                if let Some(message) = room.delete_message(request.id) {
+                    self.global.audit.record(
+                        self.id,
+                        moderator,
+                        AuditAction::DeleteMessage {
+                            room: room.id.id(),
+                            message_id: message.id,
+                            had_drawing: message.drawing.is_some(),
+                            author: message.persona,
+                            message: message.message,
+                        },
+                    );
+                }
+            }
+            Opcode::React => {
+                let Some(request) = self.parse_data::<responses::React>(data) else {
+                    return;
+                };
+
+                let Some(subscription) = self.subscription(request.room) else {
+                    tracing::debug!("not in that room, ignoring request");
+                    return;
+                };
+                if subscription.spectator || self.global.is_muted(self.session.ip) {
+                    tracing::debug!("socket {} can't react right now, ignoring", self.id);
+                    return;
+                }
+
+                let settings = &self.global.settings.reactions;
+                if !settings.stamps.contains(&request.stamp) {
+                    tracing::debug!("unknown stamp {:?}, ignoring request", request.stamp);
+                    return;
+                }
+
+                if let Err(e) = subscription.room.react(
+                    request.id,
+                    &request.stamp,
+                    self.id,
+                    !request.remove,
+                    settings.max_per_message,
+                ) {
+                    tracing::debug!("couldn't react to message {}: {e:?}", request.id);
                 }
-                let _ = room.send(RoomMessage {
-                    persona: MessagePersona::from_persona(&persona),
-                    message,
-                    drawing: msg.drawing,
-                });
             }
             Opcode::WhoAmI => {
                 tracing::debug!("received who am i request");
 
-                let persona = self
-                    .persona
-                    .try_lock()
-                    .expect("failed to lock socket's persona")
-                    .clone();
+                let persona = self.persona.lock().unwrap().clone();
                 let persona = responses::Persona::from(persona);
 
                 self.send(responses::WhoAmI { persona }).await;
@@ -247,37 +810,737 @@ impl SocketConnection {
 
                 self.send(responses::PublicRoomStatus {
                     public_rooms: self.global.get_rooms().iter().map(|r| r.into()).collect(),
+                    removed: Vec::new(),
                 })
                 .await;
             }
+            Opcode::SubscribeLobby => {
+                let Some(subscribe) = self.parse_data::<responses::SubscribeLobby>(data) else {
+                    return;
+                };
+
+                if !subscribe.subscribe {
+                    tracing::debug!("unsubscribing from lobby updates");
+                    self.lobby_subscription = None;
+                    return;
+                }
+
+                tracing::debug!("subscribing to lobby updates");
+                self.lobby_subscription = Some(self.global.lobby.subscribe());
+                self.send_lobby_snapshot().await;
+            }
+            Opcode::ModeratorLogin => {
+                let Some(login) = self.parse_data::<responses::ModeratorLogin>(data) else {
+                    return;
+                };
+
+                let moderator = self.global.check_moderator_secret(&login.secret);
+                if moderator {
+                    tracing::info!("socket {} logged in as a moderator", self.id);
+                } else {
+                    tracing::warn!("socket {} failed to log in as a moderator", self.id);
+                }
+                self.session.set_moderator(moderator);
+
+                self.send(responses::ModeratorLogin {
+                    secret: String::new(),
+                    moderator,
+                })
+                .await;
+            }
+            Opcode::Kick => {
+                let Some(kick) = self.parse_data::<responses::Kick>(data) else {
+                    return;
+                };
+
+                let Some(target) = self.moderation_target(kick.persona_id) else {
+                    return;
+                };
+                let Some(room) = self
+                    .global
+                    .get_room(kick.room.into())
+                    .filter(|r| r.has_persona(target.id))
+                else {
+                    tracing::debug!("{} isn't in room {}, ignoring", target.id, kick.room);
+                    return;
+                };
+                tracing::info!(
+                    "moderator {} kicked {} from room {}",
+                    self.id,
+                    target.id,
+                    kick.room
+                );
+                target.notify(SessionEvent::Kicked {
+                    room: Some(room.id),
+                    reason: kick.reason,
+                });
+            }
+            Opcode::Mute => {
+                let Some(mute) = self.parse_data::<responses::Mute>(data) else {
+                    return;
+                };
+
+                let Some(target) = self.moderation_target(mute.persona_id) else {
+                    return;
+                };
+                tracing::info!(
+                    "moderator {} muted {} for {}s",
+                    self.id,
+                    target.id,
+                    mute.duration
+                );
+                let duration = self
+                    .global
+                    .mute(target.ip, Duration::from_secs(mute.duration));
+                target.notify(SessionEvent::Muted {
+                    duration,
+                    reason: mute.reason,
+                });
+            }
+            Opcode::Ban => {
+                let Some(ban) = self.parse_data::<responses::Ban>(data) else {
+                    return;
+                };
+
+                let Some(target) = self.moderation_target(ban.persona_id) else {
+                    return;
+                };
+                let duration = ban.duration.map(Duration::from_secs);
+                let ban_target = if ban.by_ip {
+                    BanTarget::Ip(target.ip)
+                } else {
+                    BanTarget::Session(target.id)
+                };
+                tracing::info!("moderator {} banned {:?}", self.id, ban_target);
+
+                self.global.ban(Ban::new(ban_target, duration, ban.reason));
+            }
+            Opcode::RenameRoom => {
+                let Some(rename) = self.parse_data::<responses::RenameRoom>(data) else {
+                    return;
+                };
+
+                let Some(room) = self.owned_room(rename.room) else {
+                    return;
+                };
+
+                let name: String = rename.name.trim().chars().take(32).collect();
+                if name.chars().count() < 3 {
+                    tracing::debug!("room name is too short, ignoring");
+                    return;
+                }
+                let Verdict::Pass(name) = self.global.filter.check_name(&name) else {
+                    tracing::debug!("room name rejected by the filter, ignoring");
+                    return;
+                };
+
+                tracing::debug!("renaming room {} to {name}", room.id.id());
+                room.rename(name.clone());
+                let _ = room.send(RoomMessage::system(format!("Room renamed to {name}"), None));
+            }
+            Opcode::LockRoom => {
+                let Some(lock) = self.parse_data::<responses::LockRoom>(data) else {
+                    return;
+                };
+
+                let Some(room) = self.owned_room(lock.room) else {
+                    return;
+                };
+
+                room.set_locked(lock.locked);
+                let message = if lock.locked {
+                    "The room is now locked, nobody else can join"
+                } else {
+                    "The room is now unlocked"
+                };
+                let _ = room.send(RoomMessage::system(message.to_string(), None));
+            }
+            Opcode::CreateInvite => {
+                let Some(request) = self.parse_data::<responses::CreateInvite>(data) else {
+                    return;
+                };
+
+                // invites get past the lock, so only the owner hands them out
+                let Some(room) = self.owned_room(request.room) else {
+                    return;
+                };
+                if request.max_uses == Some(0) {
+                    tracing::debug!("invite would be used up from the start, ignoring");
+                    return;
+                }
+
+                let invite = Invite::new(
+                    room.id,
+                    self.id,
+                    request
+                        .expires_in
+                        .map(Duration::from_secs)
+                        .unwrap_or(INVITE_DEFAULT_EXPIRY),
+                    request.max_uses,
+                );
+                tracing::debug!(
+                    "socket {} minted an invite to room {}",
+                    self.id,
+                    room.id.id()
+                );
+
+                let response = responses::CreateInvite {
+                    room: Some(room.id.id()),
+                    token: Some(invite.token.clone()),
+                    expires_in: Some(invite.expires_at.duration_since(Instant::now()).as_secs()),
+                    max_uses: invite.max_uses,
+                };
+                self.global.add_invite(invite);
+                self.send(response).await;
+            }
+            Opcode::RevokeInvite => {
+                let Some(request) = self.parse_data::<responses::RevokeInvite>(data) else {
+                    return;
+                };
+
+                let Some(room) = self.owned_room(request.room) else {
+                    return;
+                };
+
+                let (revoked, code) = self.global.revoke_invites(&room, request.token.as_deref());
+                tracing::debug!("revoked {revoked} invites to room {}", room.id.id());
+                self.send(responses::RevokeInvite {
+                    room: Some(room.id.id()),
+                    token: request.token,
+                    revoked,
+                    code,
+                })
+                .await;
+            }
+            Opcode::KickMember => {
+                let Some(kick) = self.parse_data::<responses::KickMember>(data) else {
+                    return;
+                };
+
+                let Some(room) = self.owned_room(kick.room) else {
+                    return;
+                };
+                if kick.persona_id == self.id || !room.has_persona(kick.persona_id) {
+                    tracing::debug!("persona isn't someone else in the room, ignoring");
+                    return;
+                }
+
+                if let Some(target) = self.global.get_session(kick.persona_id) {
+                    target.notify(SessionEvent::Kicked {
+                        room: Some(room.id),
+                        reason: None,
+                    });
+                }
+            }
+            Opcode::TransferOwnership => {
+                let Some(transfer) = self.parse_data::<responses::TransferOwnership>(data) else {
+                    return;
+                };
+
+                let Some(room) = self.owned_room(transfer.room) else {
+                    return;
+                };
+                let Some(target) = self
+                    .global
+                    .get_session(transfer.persona_id)
+                    .filter(|s| room.has_persona(s.id))
+                else {
+                    tracing::debug!("new owner isn't in the room, ignoring");
+                    return;
+                };
+
+                room.set_owner(target.id);
+                let _ = room.send(RoomMessage::system(
+                    format!("{} is now the room owner", target.name()),
+                    None,
+                ));
+            }
             _ => (),
         }
     }
 
-    async fn leave_room(&mut self) {
-        if let Some(mut room) = self.room_subscription.take() {
-            let persona = self
-                .persona
-                .try_lock()
-                .expect("failed to lock persona")
-                .clone();
-            _ = room.send_bye(&persona).await;
-            drop(persona);
-            tracing::debug!("socket {} is leaving room {}", self.id, room.room.id.id());
+    async fn send_lobby_update(&mut self, update: LobbyUpdate) {
+        if let Some(pop) = update.population {
+            self.send(responses::ServerPopulation { pop }).await;
+        }
+
+        if !update.rooms.is_empty() || !update.removed.is_empty() {
+            self.send(responses::PublicRoomStatus {
+                public_rooms: update.rooms,
+                removed: update.removed,
+            })
+            .await;
+        }
+    }
+
+    async fn send_lobby_snapshot(&mut self) {
+        self.send_lobby_update(LobbyUpdate {
+            population: Some(self.global.get_active_connections()),
+            rooms: self.global.get_rooms().iter().map(|r| r.into()).collect(),
+            removed: Vec::new(),
+        })
+        .await;
+    }
+
+    /// A system message only this socket sees.
+    async fn tell(&mut self, room: Option<RoomId>, message: &str) {
+        let mut echo = responses::EchoMessage::from(RoomMessage::system(message.to_string(), None));
+        if let Some(room) = room {
+            echo = echo.in_room(room);
+        }
+        self.send(echo).await;
+    }
+
+    /// Runs a message through the same length and filter checks everywhere messages are sent.
+    /// Nothing if it got rejected, and the flag is set when only the sender should see it.
+    fn compose_message(
+        &self,
+        message: String,
+        drawing: Option<String>,
+    ) -> Option<(RoomMessage, bool)> {
+        let persona = self.persona.lock().unwrap().clone();
+
+        let mut message = message;
+        if message.len() > 165 {
+            message = message.chars().take(165).collect()
+        }
+
+        let verdict = self.global.filter.check_message(&message);
+        let mut message =
+            RoomMessage::new(MessagePersona::from_persona(&persona), message, drawing);
+        match verdict {
+            Verdict::Pass(filtered) => message.message = filtered,
+            Verdict::Reject => {
+                tracing::debug!("message rejected by the filter, dropping it");
+                return None;
+            }
+            Verdict::ShadowDrop => {
+                tracing::debug!("message shadow dropped by the filter");
+                return Some((message, true));
+            }
+        }
+        Some((message, false))
+    }
+
+    fn can_message(&self, target: &Session) -> bool {
+        if target.is_ignoring(self.id) {
+            return false;
+        }
+        match target.dm_policy() {
+            DirectMessagePolicy::Nobody => false,
+            DirectMessagePolicy::Anyone => true,
+            DirectMessagePolicy::SharedRoom => self
+                .rooms
+                .iter()
+                .any(|s| !s.spectator && s.room.has_persona(target.id)),
+        }
+    }
+
+    /// One of the joined rooms, the first one if no id is given.
+    fn subscription(&self, id: Option<mtid::Ttid>) -> Option<&RoomSubscription> {
+        match id {
+            Some(id) => self.rooms.iter().find(|s| s.room.id.id() == id),
+            None => self.rooms.first(),
+        }
+    }
+
+    /// A joined room this socket owns, the first one if no id is given.
+    fn owned_room(&self, id: Option<mtid::Ttid>) -> Option<Room> {
+        let room = self
+            .rooms
+            .iter()
+            .map(|s| &s.room)
+            .find(|r| r.is_owner(self.id) && id.is_none_or(|id| r.id.id() == id))
+            .cloned();
+        if room.is_none() {
+            tracing::debug!("socket {} doesn't own that room, ignoring", self.id);
+        }
+        room
+    }
+
+    fn moderation_target(&self, persona_id: Uuid) -> Option<Arc<Session>> {
+        if !self.session.is_moderator() {
+            tracing::warn!("socket {} tried to moderate without being one", self.id);
+            return None;
+        }
+
+        let target = self.global.get_session(persona_id);
+        if target.is_none() {
+            tracing::debug!("no session found for persona {persona_id}");
+        }
+        target
+    }
+
+    async fn handle_event(&mut self, event: SessionEvent) -> ControlFlow<()> {
+        tracing::debug!("received session event: {:?}", event);
+        match event {
+            SessionEvent::Kicked { room, reason } => {
+                if room.is_some_and(|room| self.subscription(Some(room.id())).is_none()) {
+                    tracing::debug!("kicked from a room we already left, ignoring");
+                    return ControlFlow::Continue(());
+                }
+
+                self.remove_from_rooms(room, "was kicked").await;
+                self.send(responses::ModerationNotice {
+                    action: responses::ModerationAction::Kick,
+                    reason,
+                    duration: None,
+                })
+                .await;
+            }
+            SessionEvent::Muted { duration, reason } => {
+                // a zero duration is how mutes get lifted
+                let (notice, action, duration) = if duration.is_zero() {
+                    (
+                        "was unmuted".to_string(),
+                        responses::ModerationAction::Unmute,
+                        None,
+                    )
+                } else {
+                    (
+                        format!("was muted for {}s", duration.as_secs()),
+                        responses::ModerationAction::Mute,
+                        Some(duration.as_secs()),
+                    )
+                };
+                let name = self.session.name();
+                for room in self.rooms.iter().filter(|s| !s.spectator) {
+                    let _ = room.send(RoomMessage::system(format!("{name} {notice}"), None));
+                }
+                self.send(responses::ModerationNotice {
+                    action,
+                    reason,
+                    duration,
+                })
+                .await;
+            }
+            SessionEvent::Banned { duration, reason } => {
+                self.remove_from_rooms(None, "was banned").await;
+                self.send(responses::ModerationNotice {
+                    action: responses::ModerationAction::Ban,
+                    reason,
+                    duration: duration.map(|d| d.as_secs()),
+                })
+                .await;
+                _ = self.socket.send(ws::Message::Close(None)).await;
+                return ControlFlow::Break(());
+            }
+            SessionEvent::Knocked { room, persona } => {
+                if self.subscription(Some(room.id())).is_none()
+                    || self.session.is_ignoring(persona.id)
+                {
+                    return ControlFlow::Continue(());
+                }
+                self.send(responses::KnockPrompt {
+                    id: room.id(),
+                    persona,
+                })
+                .await;
+            }
+            SessionEvent::KnockAnswered { room, approved } => {
+                let Some(knocking) = self.knocking.take_if(|k| k.room.id == room) else {
+                    tracing::debug!("answer to a knock we gave up on, ignoring");
+                    return ControlFlow::Continue(());
+                };
+
+                let state = if !approved {
+                    responses::KnockState::Denied
+                } else {
+                    match self.join_invited(knocking.room, knocking.keep_others).await {
+                        Ok(()) => responses::KnockState::Approved,
+                        Err(e) => {
+                            tracing::debug!("let in, but couldn't join: {e:?}");
+                            responses::KnockState::Denied
+                        }
+                    }
+                };
+                self.send(responses::KnockStatus {
+                    id: room.id(),
+                    state,
+                })
+                .await;
+            }
+            SessionEvent::Mentioned { room, message } => {
+                // the echo already got filtered the same way, so match it
+                if self.subscription(Some(room.id())).is_none()
+                    || self.session.is_ignoring(message.persona.id)
+                {
+                    return ControlFlow::Continue(());
+                }
+                self.send(responses::Mention {
+                    room: room.id(),
+                    id: message.id,
+                    persona: message.persona,
+                    message: message.message,
+                })
+                .await;
+            }
+            SessionEvent::DirectMessage { message } => {
+                self.send(responses::DirectMessage::new(self.id, message))
+                    .await;
+            }
+            SessionEvent::SlotFreed { room } => {
+                let Some(waiting) = self.waiting.take_if(|w| w.ticket.room.id == room) else {
+                    tracing::debug!("slot freed in a room we're not waiting for, ignoring");
+                    return ControlFlow::Continue(());
+                };
+
+                let room = waiting.ticket.room.clone();
+                match self.join(room.clone(), waiting.keep_others).await {
+                    Ok(()) => {
+                        self.send(responses::WaitlistStatus {
+                            id: room.id.id(),
+                            state: responses::WaitlistState::Admitted,
+                            position: None,
+                        })
+                        .await;
+                    }
+                    Err(JoinError::Full) => {
+                        tracing::debug!("slot got taken before we got to it, back to waiting");
+                        self.waiting = Some(waiting);
+                    }
+                    Err(e @ (JoinError::Locked | JoinError::Closed)) => {
+                        tracing::debug!("room can't be joined anymore: {e:?}");
+                        self.send(responses::WaitlistStatus {
+                            id: room.id.id(),
+                            state: responses::WaitlistState::Cancelled,
+                            position: None,
+                        })
+                        .await;
+                    }
+                }
+            }
+        }
+
+        ControlFlow::Continue(())
+    }
+
+    /// Like leaving a room (or all of them if none is given), but the room is told why instead
+    /// of getting the usual goodbye.
+    async fn remove_from_rooms(&mut self, room: Option<RoomId>, what_happened: &str) {
+        let name = self.session.name();
+        let (removed, kept) = std::mem::take(&mut self.rooms)
+            .into_iter()
+            .partition(|s| room.is_none_or(|id| s.room.id == id));
+        self.rooms = kept;
+
+        for subscription in removed {
+            self.last_room = Some(subscription.room.id);
+            // being thrown out means no coming back by id
+            subscription.room.forget_member(self.id);
+            if !subscription.spectator {
+                let _ =
+                    subscription.send(RoomMessage::system(format!("{name} {what_happened}"), None));
+            }
+            tracing::debug!(
+                "socket {} was removed from room {}",
+                self.id,
+                subscription.room.id.id()
+            );
         }
     }
+
+    async fn join(&mut self, room: Room, keep_others: bool) -> Result<(), JoinError> {
+        if self.subscription(Some(room.id.id())).is_some() {
+            return Ok(());
+        }
+        let subscription = room.subscribe(self.persona.clone()).await?;
+        self.enter_open(subscription, keep_others).await
+    }
+
+    async fn join_invited(&mut self, room: Room, keep_others: bool) -> Result<(), JoinError> {
+        if self.subscription(Some(room.id.id())).is_some() {
+            return Ok(());
+        }
+        let subscription = room.subscribe_invited(self.persona.clone()).await?;
+        self.enter_open(subscription, keep_others).await
+    }
+
+    /// Enters a room that just let us in, unless it got closed in the meantime. Empty overflow
+    /// instances get merged away, which can happen right between picking one and subscribing.
+    /// Only once we're sure to get in are other rooms left to make space for it.
+    async fn enter_open(
+        &mut self,
+        subscription: RoomSubscription,
+        keep_others: bool,
+    ) -> Result<(), JoinError> {
+        if self.global.get_room(subscription.room.id).is_none() {
+            return Err(JoinError::Closed);
+        }
+        self.make_space(keep_others).await;
+        self.enter(subscription).await;
+        Ok(())
+    }
+
+    async fn enter(&mut self, mut subscription: RoomSubscription) {
+        // got in somewhere, whatever we were waiting for doesn't matter anymore
+        self.waiting = None;
+        if let Some(knocking) = self.knocking.take() {
+            knocking.room.take_knock(self.id);
+        }
+        tracing::debug!("subscribed to room successfully, sending system message");
+
+        let persona = self.persona.lock().unwrap().clone();
+        let room = subscription.room.id;
+        for (message, reactions) in std::mem::take(&mut subscription.backlog) {
+            if self.session.is_ignoring(message.persona.id) {
+                continue;
+            }
+            self.send(
+                responses::EchoMessage::from(message)
+                    .in_room(room)
+                    .replayed(reactions),
+            )
+            .await;
+        }
+
+        // spectators come and go quietly
+        if !subscription.spectator {
+            subscription.send_hello(&persona).await;
+        }
+
+        self.rooms.push(subscription);
+    }
+
+    async fn wait_for(&mut self, room: Room, keep_others: bool) {
+        let now = tokio::time::Instant::now();
+        self.waiting = Some(Waiting {
+            ticket: room.enqueue(self.session.clone()),
+            keep_others,
+            deadline: now + WAITLIST_TIMEOUT,
+            next_update: now,
+        });
+    }
+
+    /// Sends the current place in line, or gives up once waiting took too long.
+    async fn update_waitlist(&mut self) {
+        let Some(waiting) = &mut self.waiting else {
+            return;
+        };
+        let id = waiting.ticket.room.id;
+
+        let state = if self.global.get_room(id).is_none() {
+            responses::WaitlistState::Cancelled
+        } else if waiting.deadline <= tokio::time::Instant::now() {
+            responses::WaitlistState::TimedOut
+        } else {
+            let position = waiting.ticket.position();
+            waiting.next_update += WAITLIST_UPDATE_INTERVAL;
+            self.send(responses::WaitlistStatus {
+                id: id.id(),
+                state: responses::WaitlistState::Waiting,
+                position,
+            })
+            .await;
+            return;
+        };
+
+        tracing::debug!("done waiting for room {}: {state:?}", id.id());
+        self.waiting = None;
+        self.send(responses::WaitlistStatus {
+            id: id.id(),
+            state,
+            position: None,
+        })
+        .await;
+    }
+
+    /// Joins whichever instance of a public room fits best, opening a new one if needed.
+    /// Returns the instance it got into.
+    async fn join_any(&mut self, id: RoomId, keep_others: bool) -> Option<RoomId> {
+        // an empty instance can get merged away between picking it and joining it, just pick again
+        for _ in 0..3 {
+            let Some(room) = self.global.best_instance(id) else {
+                tracing::debug!("no public room with id {}", id.id());
+                return None;
+            };
+
+            match self.join(room.clone(), keep_others).await {
+                Ok(()) => {
+                    tracing::debug!("joined instance {} of room {}", room.instance, id.id());
+                    return Some(room.id);
+                }
+                Err(e) => tracing::debug!("cannot join instance {}: {e:?}", room.instance),
+            }
+        }
+        tracing::debug!("gave up looking for an instance of room {}", id.id());
+        None
+    }
+
+    async fn leave_room(&mut self, id: RoomId) {
+        let Some(index) = self.rooms.iter().position(|s| s.room.id == id) else {
+            return;
+        };
+        let mut room = self.rooms.remove(index);
+
+        self.last_room = Some(room.room.id);
+        let persona = self.persona.lock().unwrap().clone();
+        if !room.spectator {
+            room.send_bye(&persona).await;
+        }
+        tracing::debug!("socket {} is leaving room {}", self.id, room.room.id.id());
+    }
+
+    async fn leave_rooms(&mut self) {
+        while let Some(room) = self.rooms.last() {
+            self.leave_room(room.room.id).await;
+        }
+    }
+
+    /// Makes space for one more room. Without `keep_others` that means leaving everything like
+    /// before sockets could be in several rooms, otherwise only the oldest rooms are left.
+    /// Called once the new room has let us in, so a failed join doesn't cost anything.
+    async fn make_space(&mut self, keep_others: bool) {
+        if !keep_others {
+            self.leave_rooms().await;
+            return;
+        }
+
+        let max = self.global.settings.rooms.max_subscriptions.max(1);
+        while self.rooms.len() >= max {
+            let oldest = self.rooms[0].room.id;
+            self.leave_room(oldest).await;
+        }
+    }
+}
+
+fn message_len(message: &ws::Message) -> u64 {
+    let len = match message {
+        ws::Message::Text(text) => text.len(),
+        ws::Message::Binary(data) | ws::Message::Ping(data) | ws::Message::Pong(data) => data.len(),
+        ws::Message::Close(_) => 0,
+    };
+    len as u64
+}
+
+#[derive(Debug, serde::Deserialize)]
+pub struct ConnectParams {
+    resume: Option<String>, // resume token from an earlier handshake
 }
 
 #[axum::debug_handler]
 pub async fn handler(
     State(global): State<Arc<GlobalState>>,
+    ClientIp(ip): ClientIp,
+    Query(params): Query<ConnectParams>,
     ws: WebSocketUpgrade,
 ) -> impl IntoResponse {
+    let started = Instant::now();
+    if global.is_address_banned(ip) {
+        tracing::debug!("refusing socket connection from banned address {ip}");
+        return StatusCode::FORBIDDEN.into_response();
+    }
+
+    // a stale or unknown token just gets a fresh session
+    let resumed = params.resume.and_then(|token| global.resume(&token));
+
     ws.on_upgrade(move |ws| async move {
-        tracing::debug!("accepting new socket connection");
+        tracing::debug!("accepting new socket connection from {ip}");
         let guard = global.active_connection_guard();
 
-        let mut socket = SocketConnection::new(ws, guard, global);
-        tokio::spawn(async move { socket.serve().await });
+        let mut socket = SocketConnection::new(ws, ip, guard, global, resumed);
+        let span = tracing::info_span!("socket", id = %socket.id, ip = %socket.session.ip);
+        tokio::spawn(async move { socket.serve(started).await }.instrument(span));
     })
+    .into_response()
 }
@@ -0,0 +1,92 @@
+// keeping people from pretending to be someone (or something) they aren't
+
+pub const SYSTEM_NAME: &str = "System";
+pub const SYSTEM_COLOR: &str = "EDA728FF";
+
+const RESERVED_NAMES: &[&str] = &[SYSTEM_NAME];
+// redmean distance, anything below this is hard to tell apart from the system color at a glance
+const SYSTEM_COLOR_MIN_DISTANCE: f32 = 96.0;
+
+/// UTS #39 skeleton of a name, case insensitive. Two names with the same skeleton look the same,
+/// like "System" and "Sуstem" (cyrillic у).
+pub fn skeleton(name: &str) -> String {
+    unicode_security::skeleton(&name.to_lowercase())
+        .collect::<String>()
+        .to_lowercase()
+}
+
+pub fn is_confusable(a: &str, b: &str) -> bool {
+    skeleton(a) == skeleton(b)
+}
+
+pub fn is_reserved_name(name: &str) -> bool {
+    let name = skeleton(name);
+    RESERVED_NAMES
+        .iter()
+        .any(|reserved| name.contains(&skeleton(reserved)))
+}
+
+fn parse_rgb(hex: &str) -> Option<[u8; 3]> {
+    let hex = hex.trim_start_matches('#');
+    if hex.len() < 6 || !hex.is_ascii() {
+        return None;
+    }
+
+    let channel = |i: usize| u8::from_str_radix(&hex[i..i + 2], 16).ok();
+    Some([channel(0)?, channel(2)?, channel(4)?])
+}
+
+/// Colors we can't parse count as too close, there's no telling what the client does with them.
+pub fn is_system_like_color(hex: &str) -> bool {
+    let (Some(color), Some(system)) = (parse_rgb(hex), parse_rgb(SYSTEM_COLOR)) else {
+        return true;
+    };
+
+    let [r1, g1, b1] = color.map(f32::from);
+    let [r2, g2, b2] = system.map(f32::from);
+    let mean_r = (r1 + r2) / 2.0;
+    let (dr, dg, db) = (r1 - r2, g1 - g2, b1 - b2);
+    let distance = ((2.0 + mean_r / 256.0) * dr * dr
+        + 4.0 * dg * dg
+        + (2.0 + (255.0 - mean_r) / 256.0) * db * db)
+        .sqrt();
+
+    distance < SYSTEM_COLOR_MIN_DISTANCE
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lookalikes_collide() {
+        assert!(is_confusable("System", "Sуstem")); // cyrillic у
+        assert!(is_confusable("alice", "ALICE"));
+        assert!(is_confusable("paypal", "pаypаl")); // cyrillic а
+        assert!(!is_confusable("alice", "bob"));
+    }
+
+    #[test]
+    fn reserved_names_and_their_lookalikes() {
+        assert!(is_reserved_name("System"));
+        assert!(is_reserved_name("sуstem"));
+        assert!(is_reserved_name("the System bot"));
+        assert!(!is_reserved_name("Sys"));
+    }
+
+    #[test]
+    fn colors_near_the_system_color() {
+        assert!(is_system_like_color(SYSTEM_COLOR));
+        assert!(is_system_like_color("#EDA728"));
+        assert!(is_system_like_color("EAA52AFF"));
+        assert!(!is_system_like_color("3366FFFF"));
+        assert!(!is_system_like_color("000000FF"));
+    }
+
+    #[test]
+    fn unparseable_colors_are_refused() {
+        assert!(is_system_like_color(""));
+        assert!(is_system_like_color("zzzzzz"));
+        assert!(is_system_like_color("ééé"));
+    }
+}
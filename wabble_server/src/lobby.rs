@@ -0,0 +1,114 @@
+use std::{collections::HashMap, sync::Arc, time::Duration};
+
+use tokio::sync::{Notify, broadcast};
+
+use crate::{global::GlobalState, responses::PublicRoomInfo};
+
+// changes are gathered for this long before anything gets pushed, rooms can churn a lot
+const DEBOUNCE: Duration = Duration::from_millis(500);
+
+pub type LobbyTx = broadcast::Sender<LobbyUpdate>;
+pub type LobbyRx = broadcast::Receiver<LobbyUpdate>;
+
+/// What changed in the lobby since the last update. Only the rooms that changed are sent.
+#[derive(Debug, Clone, Default)]
+pub struct LobbyUpdate {
+    pub population: Option<usize>,
+    pub rooms: Vec<PublicRoomInfo>,
+    pub removed: Vec<mtid::Ttid>,
+}
+
+impl LobbyUpdate {
+    pub fn is_empty(&self) -> bool {
+        self.population.is_none() && self.rooms.is_empty() && self.removed.is_empty()
+    }
+}
+
+#[derive(Debug)]
+pub struct Lobby {
+    changed: Arc<Notify>,
+    tx: LobbyTx,
+}
+
+impl Default for Lobby {
+    fn default() -> Self {
+        let (tx, _rx) = broadcast::channel(16);
+        Self {
+            changed: Arc::new(Notify::new()),
+            tx,
+        }
+    }
+}
+
+impl Lobby {
+    /// Handed to anything that can change what the lobby sees, poked on every change.
+    pub fn change_notifier(&self) -> Arc<Notify> {
+        self.changed.clone()
+    }
+
+    pub fn subscribe(&self) -> LobbyRx {
+        self.tx.subscribe()
+    }
+}
+
+#[derive(Debug, Default)]
+struct Snapshot {
+    population: usize,
+    rooms: HashMap<mtid::Ttid, PublicRoomInfo>,
+}
+
+impl Snapshot {
+    fn take(global: &GlobalState) -> Self {
+        Self {
+            population: global.get_active_connections(),
+            rooms: global
+                .get_rooms()
+                .iter()
+                .map(|r| (r.id.id(), PublicRoomInfo::from(r)))
+                .collect(),
+        }
+    }
+
+    fn diff(&self, current: &Self) -> LobbyUpdate {
+        let mut rooms: Vec<_> = current
+            .rooms
+            .iter()
+            .filter(|(id, room)| self.rooms.get(id) != Some(room))
+            .map(|(_, room)| room.clone())
+            .collect();
+        rooms.sort_by(|a, b| a.name.cmp(&b.name));
+
+        LobbyUpdate {
+            population: (self.population != current.population).then_some(current.population),
+            rooms,
+            removed: self
+                .rooms
+                .keys()
+                .filter(|id| !current.rooms.contains_key(id))
+                .copied()
+                .collect(),
+        }
+    }
+}
+
+pub async fn run(global: Arc<GlobalState>) {
+    let mut last = Snapshot::take(&global);
+
+    loop {
+        global.lobby.changed.notified().await;
+        tokio::time::sleep(DEBOUNCE).await;
+
+        // waiting out the debounce first means someone hopping rooms doesn't tear an instance down
+        global.prune_overflow();
+        let current = Snapshot::take(&global);
+        let update = last.diff(&current);
+        last = current;
+
+        if update.is_empty() || global.lobby.tx.receiver_count() == 0 {
+            continue;
+        }
+
+        tracing::debug!("pushing lobby update: {:?}", update);
+        let _ = global.lobby.tx.send(update);
+    }
+}
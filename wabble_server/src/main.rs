@@ -3,11 +3,19 @@ use std::{sync::Arc, time::Duration};
 use rand::Rng;
 use tokio::sync::oneshot;
 
+pub mod audit;
+pub mod commands;
+pub mod filter;
 pub mod global;
 mod http;
+pub mod identity;
+pub mod lobby;
 pub mod logger;
+pub mod metrics;
 pub mod responses;
 pub mod room;
+pub mod room_code;
+pub mod session;
 pub mod settings;
 
 const FACES: &[&str] = &[":)", ":D", ":P", ":3"]; // astetic facses
@@ -29,11 +37,15 @@ async fn main() {
     let (shutdown_tx, shutdown_rx) = oneshot::channel();
     let global = Arc::new(global::GlobalState::new(settings));
 
-    let http_server = tokio::spawn(http::run(global, shutdown_rx));
+    tokio::spawn(filter::watch(global.clone()));
+    tokio::spawn(lobby::run(global.clone()));
+    let http_server = tokio::spawn(http::run(global.clone(), shutdown_rx));
 
     let shutdown = tokio::spawn(async move {
         tokio::signal::ctrl_c().await.ok();
         tracing::info!("Received ctrl-c signal, shutting down...");
+        global.begin_shutdown();
+        tokio::time::sleep(Duration::from_secs(global.settings.http.drain_delay)).await;
         shutdown_tx.send(()).ok();
 
         tokio::time::timeout(Duration::from_secs(60), tokio::signal::ctrl_c())
@@ -0,0 +1,190 @@
+use std::{
+    fmt::Write,
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+use crate::global::GlobalState;
+
+const HANDSHAKE_BUCKETS: [f64; 8] = [0.001, 0.005, 0.01, 0.025, 0.05, 0.1, 0.5, 1.0]; // seconds
+
+#[derive(Debug, Default)]
+pub struct Counter(AtomicU64);
+
+impl Counter {
+    pub fn inc(&self) {
+        self.add(1);
+    }
+
+    pub fn add(&self, value: u64) {
+        self.0.fetch_add(value, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Histogram {
+    buckets: [AtomicU64; HANDSHAKE_BUCKETS.len()],
+    sum_micros: AtomicU64,
+    count: AtomicU64,
+}
+
+impl Histogram {
+    pub fn observe(&self, duration: Duration) {
+        let secs = duration.as_secs_f64();
+        for (bucket, bound) in self.buckets.iter().zip(HANDSHAKE_BUCKETS) {
+            if secs <= bound {
+                bucket.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        self.sum_micros
+            .fetch_add(duration.as_micros() as u64, Ordering::Relaxed);
+        self.count.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Everything the server counts, rendered in the Prometheus text format on `/metrics`.
+#[derive(Debug, Default)]
+pub struct Metrics {
+    pub messages_relayed: Counter,
+    pub drawings_relayed: Counter,
+    pub direct_messages_relayed: Counter,
+    pub bytes_in: Counter,
+    pub bytes_out: Counter,
+    pub broadcast_lag_events: Counter,
+    pub broadcast_lag_skipped: Counter,
+    pub parse_errors: Counter,
+    pub handshake_duration: Histogram,
+}
+
+fn write_metric(
+    out: &mut String,
+    name: &str,
+    kind: &str,
+    help: &str,
+    value: impl std::fmt::Display,
+) {
+    let _ = writeln!(out, "# HELP {name} {help}");
+    let _ = writeln!(out, "# TYPE {name} {kind}");
+    let _ = writeln!(out, "{name} {value}");
+}
+
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', r"\\")
+        .replace('"', "\\\"")
+        .replace('\n', r"\n")
+}
+
+impl Metrics {
+    pub fn render(&self, global: &GlobalState) -> String {
+        let mut out = String::new();
+
+        write_metric(
+            &mut out,
+            "wabble_active_connections",
+            "gauge",
+            "Currently connected sockets.",
+            global.get_active_connections(),
+        );
+
+        let rooms = global.get_all_rooms();
+        let public = rooms.iter().filter(|r| r.is_public).count();
+        let _ = writeln!(out, "# HELP wabble_rooms Rooms currently alive, by type.");
+        let _ = writeln!(out, "# TYPE wabble_rooms gauge");
+        let _ = writeln!(out, "wabble_rooms{{type=\"public\"}} {public}");
+        let _ = writeln!(
+            out,
+            "wabble_rooms{{type=\"private\"}} {}",
+            rooms.len() - public
+        );
+
+        let _ = writeln!(
+            out,
+            "# HELP wabble_public_room_members Members of each public room."
+        );
+        let _ = writeln!(out, "# TYPE wabble_public_room_members gauge");
+        for room in global.get_rooms() {
+            let _ = writeln!(
+                out,
+                "wabble_public_room_members{{room=\"{}\",name=\"{}\"}} {}",
+                room.id.id(),
+                escape_label(&room.name()),
+                room.current_connections()
+            );
+        }
+
+        for (name, help, counter) in [
+            (
+                "wabble_messages_relayed_total",
+                "Messages broadcast to rooms.",
+                &self.messages_relayed,
+            ),
+            (
+                "wabble_drawings_relayed_total",
+                "Messages with a drawing broadcast to rooms.",
+                &self.drawings_relayed,
+            ),
+            (
+                "wabble_direct_messages_relayed_total",
+                "Direct messages delivered to another session.",
+                &self.direct_messages_relayed,
+            ),
+            (
+                "wabble_bytes_in_total",
+                "Bytes received from sockets.",
+                &self.bytes_in,
+            ),
+            (
+                "wabble_bytes_out_total",
+                "Bytes sent to sockets.",
+                &self.bytes_out,
+            ),
+            (
+                "wabble_broadcast_lag_events_total",
+                "Times a socket fell behind its room broadcast.",
+                &self.broadcast_lag_events,
+            ),
+            (
+                "wabble_broadcast_lag_skipped_total",
+                "Messages skipped by lagging sockets.",
+                &self.broadcast_lag_skipped,
+            ),
+            (
+                "wabble_parse_errors_total",
+                "Socket messages that couldn't be parsed.",
+                &self.parse_errors,
+            ),
+        ] {
+            write_metric(&mut out, name, "counter", help, counter.get());
+        }
+
+        let histogram = &self.handshake_duration;
+        let name = "wabble_handshake_duration_seconds";
+        let _ = writeln!(
+            out,
+            "# HELP {name} Time from the upgrade request to the handshake being sent."
+        );
+        let _ = writeln!(out, "# TYPE {name} histogram");
+        for (bucket, bound) in histogram.buckets.iter().zip(HANDSHAKE_BUCKETS) {
+            let _ = writeln!(
+                out,
+                "{name}_bucket{{le=\"{bound}\"}} {}",
+                bucket.load(Ordering::Relaxed)
+            );
+        }
+        let count = histogram.count.load(Ordering::Relaxed);
+        let _ = writeln!(out, "{name}_bucket{{le=\"+Inf\"}} {count}");
+        let _ = writeln!(
+            out,
+            "{name}_sum {}",
+            histogram.sum_micros.load(Ordering::Relaxed) as f64 / 1_000_000.0
+        );
+        let _ = writeln!(out, "{name}_count {count}");
+
+        out
+    }
+}
@@ -1,6 +1,12 @@
 use axum::extract::ws::Message;
 
-use crate::room::{self, MessagePersona, Room};
+use crate::{
+    room::{self, MessagePersona, Room},
+    session,
+};
+
+/// Bumped whenever the socket protocol changes in a way clients have to care about.
+pub const PROTOCOL_VERSION: u32 = 1;
 
 #[derive(
     Debug,
@@ -23,6 +29,36 @@ pub enum Opcode {
     WhoAmI = 6,
     ServerPopulation = 7,
     PublicRoomStatus = 8,
+    ModeratorLogin = 9,
+    Kick = 10,
+    Mute = 11,
+    Ban = 12,
+    ModerationNotice = 13,
+    RenameRoom = 14,
+    LockRoom = 15,
+    KickMember = 16,
+    TransferOwnership = 17,
+    SubscribeLobby = 18,
+    JoinAny = 19,
+    QuickJoin = 20,
+    LeaveWaitlist = 21,
+    WaitlistStatus = 22,
+    CreateInvite = 23,
+    RevokeInvite = 24,
+    Knock = 25,
+    KnockStatus = 26,
+    KnockPrompt = 27,
+    AnswerKnock = 28,
+    DirectMessage = 29,
+    DirectMessagePolicy = 30,
+    Ignore = 31,
+    Unignore = 32,
+    DeleteMessage = 33,
+    MessageDeleted = 34,
+    React = 35,
+    Reaction = 36,
+    Mention = 37,
+    JoinFailed = 38,
 }
 
 pub trait SocketResponse: std::fmt::Debug {
@@ -58,6 +94,7 @@ impl<D: SocketResponse + serde::Serialize> From<SocketComms<D>> for Message {
 #[derive(Debug, serde::Serialize)]
 pub struct Handshake {
     pub session_id: uuid::Uuid,
+    pub resume_token: String, // connect with `?resume=` to pick this session back up
     pub active_connections: usize,
     pub public_rooms: Vec<PublicRoomInfo>,
 }
@@ -68,21 +105,33 @@ impl SocketResponse for Handshake {
     }
 }
 
-#[derive(Debug, serde::Serialize, serde::Deserialize)]
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
 pub struct PublicRoomInfo {
     pub id: mtid::Ttid,
     pub name: String,
     pub active_connections: usize,
     pub max_connections: usize,
+    #[serde(default)]
+    pub spectators: usize,
+    #[serde(default)]
+    pub max_spectators: usize,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub topic: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub parent: Option<mtid::Ttid>, // set on overflow instances, the room they spilled out of
 }
 
 impl From<&Room> for PublicRoomInfo {
     fn from(value: &Room) -> Self {
         Self {
             id: value.id.id(),
-            name: value.name.clone(),
+            name: value.name(),
             active_connections: value.current_connections(),
             max_connections: value.max_connections,
+            spectators: value.current_spectators(),
+            max_spectators: value.max_spectators,
+            topic: value.topic.clone(),
+            parent: value.parent.map(|p| p.id()),
         }
     }
 }
@@ -116,7 +165,13 @@ impl From<room::Persona> for Persona {
 
 #[derive(Debug, serde::Deserialize)]
 pub struct JoinRoom {
-    pub id: mtid::Ttid,
+    pub id: String, // room id, word code or invite token
+    #[serde(default)]
+    pub wait: bool, // get in line if the room is full
+    #[serde(default)]
+    pub spectate: bool, // watch a public room without joining in
+    #[serde(default)]
+    pub keep_others: bool, // stay in the rooms already joined instead of leaving them
 }
 
 impl SocketResponse for JoinRoom {
@@ -129,6 +184,12 @@ impl SocketResponse for JoinRoom {
 pub struct SendMessage {
     pub message: String,
     pub drawing: Option<String>, // optional drawing data, probably RLE encoded to tinify it
+    #[serde(default)]
+    pub room: Option<mtid::Ttid>, // which of the joined rooms, the first one if not provided
+    #[serde(default)]
+    pub reply_to: Option<u64>, // id of a message still in the room's history
+    #[serde(default)]
+    pub remix: bool, // the drawing is the parent's drawing scribbled on, needs `reply_to`
 }
 
 impl SocketResponse for SendMessage {
@@ -139,9 +200,33 @@ impl SocketResponse for SendMessage {
 
 #[derive(Debug, serde::Serialize)]
 pub struct EchoMessage {
+    pub id: u64, // unique within the room, 0 for messages that never went through one
     pub message: String,
     pub drawing: Option<String>,
     pub persona: MessagePersona,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub room: Option<mtid::Ttid>, // sockets can be in several rooms at once
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub parent: Option<Box<room::MessageParent>>, // what this is a reply to
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub mentions: Vec<MessagePersona>, // colors included, duplicate names differ by color
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub reactions: Vec<room::ReactionCount>, // only on replayed history, live ones come as deltas
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub replayed: bool, // sent before joining, from the room's history
+}
+
+impl EchoMessage {
+    pub fn in_room(mut self, room: room::RoomId) -> Self {
+        self.room = Some(room.id());
+        self
+    }
+
+    pub fn replayed(mut self, reactions: Vec<room::ReactionCount>) -> Self {
+        self.replayed = true;
+        self.reactions = reactions;
+        self
+    }
 }
 
 impl SocketResponse for EchoMessage {
@@ -153,6 +238,7 @@ impl SocketResponse for EchoMessage {
 impl From<room::RoomMessage> for EchoMessage {
     fn from(value: room::RoomMessage) -> Self {
         Self {
+            id: value.id,
             message: value.message,
             drawing: value.drawing,
             persona: MessagePersona {
@@ -160,6 +246,11 @@ impl From<room::RoomMessage> for EchoMessage {
                 name: value.persona.name,
                 color: value.persona.color,
             },
+            room: None,
+            parent: value.parent,
+            mentions: value.mentions,
+            reactions: Vec::new(),
+            replayed: false,
         }
     }
 }
@@ -188,7 +279,9 @@ impl SocketResponse for ServerPopulation {
 
 #[derive(Debug, serde::Deserialize, serde::Serialize)]
 pub struct PublicRoomStatus {
-    pub public_rooms: Vec<PublicRoomInfo>,
+    pub public_rooms: Vec<PublicRoomInfo>, // only the changed ones when pushed to lobby subscribers
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub removed: Vec<mtid::Ttid>,
 }
 
 impl SocketResponse for PublicRoomStatus {
@@ -197,6 +290,469 @@ impl SocketResponse for PublicRoomStatus {
     }
 }
 
+#[derive(Debug, serde::Deserialize, serde::Serialize)]
+pub struct ModeratorLogin {
+    #[serde(skip_serializing)]
+    pub secret: String,
+    #[serde(default)]
+    pub moderator: bool, // only filled in the response
+}
+
+impl SocketResponse for ModeratorLogin {
+    fn opcode(&self) -> Opcode {
+        Opcode::ModeratorLogin
+    }
+}
+
+#[derive(Debug, serde::Deserialize)]
+pub struct Kick {
+    pub persona_id: uuid::Uuid,
+    pub room: mtid::Ttid, // the room to kick them out of
+    pub reason: Option<String>,
+}
+
+impl SocketResponse for Kick {
+    fn opcode(&self) -> Opcode {
+        Opcode::Kick
+    }
+}
+
+#[derive(Debug, serde::Deserialize)]
+pub struct Mute {
+    pub persona_id: uuid::Uuid,
+    pub duration: u64, // seconds, 0 unmutes
+    pub reason: Option<String>,
+}
+
+impl SocketResponse for Mute {
+    fn opcode(&self) -> Opcode {
+        Opcode::Mute
+    }
+}
+
+#[derive(Debug, serde::Deserialize)]
+pub struct Ban {
+    pub persona_id: uuid::Uuid,
+    #[serde(default)]
+    pub by_ip: bool, // also kick everyone else on the same address
+    pub duration: Option<u64>, // seconds, forever if not provided
+    pub reason: Option<String>,
+}
+
+impl SocketResponse for Ban {
+    fn opcode(&self) -> Opcode {
+        Opcode::Ban
+    }
+}
+
+#[derive(Debug, serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ModerationAction {
+    Kick,
+    Mute,
+    Unmute,
+    Ban,
+}
+
+#[derive(Debug, serde::Serialize)]
+pub struct ModerationNotice {
+    pub action: ModerationAction,
+    pub reason: Option<String>,
+    pub duration: Option<u64>, // seconds
+}
+
+impl SocketResponse for ModerationNotice {
+    fn opcode(&self) -> Opcode {
+        Opcode::ModerationNotice
+    }
+}
+
+#[derive(Debug, serde::Deserialize)]
+pub struct RenameRoom {
+    pub name: String,
+    #[serde(default)]
+    pub room: Option<mtid::Ttid>, // which of the joined rooms, the first one if not provided
+}
+
+impl SocketResponse for RenameRoom {
+    fn opcode(&self) -> Opcode {
+        Opcode::RenameRoom
+    }
+}
+
+#[derive(Debug, serde::Deserialize)]
+pub struct LockRoom {
+    pub locked: bool,
+    #[serde(default)]
+    pub room: Option<mtid::Ttid>, // which of the joined rooms, the first one if not provided
+}
+
+impl SocketResponse for LockRoom {
+    fn opcode(&self) -> Opcode {
+        Opcode::LockRoom
+    }
+}
+
+#[derive(Debug, serde::Deserialize)]
+pub struct KickMember {
+    pub persona_id: uuid::Uuid,
+    #[serde(default)]
+    pub room: Option<mtid::Ttid>, // which of the joined rooms, the first one if not provided
+}
+
+impl SocketResponse for KickMember {
+    fn opcode(&self) -> Opcode {
+        Opcode::KickMember
+    }
+}
+
+#[derive(Debug, serde::Deserialize)]
+pub struct TransferOwnership {
+    pub persona_id: uuid::Uuid,
+    #[serde(default)]
+    pub room: Option<mtid::Ttid>, // which of the joined rooms, the first one if not provided
+}
+
+impl SocketResponse for TransferOwnership {
+    fn opcode(&self) -> Opcode {
+        Opcode::TransferOwnership
+    }
+}
+
+#[derive(Debug, serde::Deserialize)]
+pub struct SubscribeLobby {
+    #[serde(default = "default_true")]
+    pub subscribe: bool, // false to stop getting pushed updates
+}
+
+fn default_true() -> bool {
+    true
+}
+
+impl SocketResponse for SubscribeLobby {
+    fn opcode(&self) -> Opcode {
+        Opcode::SubscribeLobby
+    }
+}
+
+#[derive(Debug, serde::Deserialize)]
+pub struct JoinAny {
+    pub id: mtid::Ttid, // any instance of the room works, the best one is picked
+    #[serde(default)]
+    pub keep_others: bool, // stay in the rooms already joined instead of leaving them
+}
+
+impl SocketResponse for JoinAny {
+    fn opcode(&self) -> Opcode {
+        Opcode::JoinAny
+    }
+}
+
+#[derive(Debug, serde::Deserialize)]
+pub struct QuickJoinRequest {
+    #[serde(default)]
+    pub keep_others: bool, // stay in the rooms already joined instead of leaving them
+}
+
+impl SocketResponse for QuickJoinRequest {
+    fn opcode(&self) -> Opcode {
+        Opcode::QuickJoin
+    }
+}
+
+#[derive(Debug, serde::Serialize)]
+pub struct QuickJoin {
+    pub room: Option<PublicRoomInfo>, // none if there was nowhere to go
+}
+
+impl SocketResponse for QuickJoin {
+    fn opcode(&self) -> Opcode {
+        Opcode::QuickJoin
+    }
+}
+
+#[derive(Debug, serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum WaitlistState {
+    Waiting,
+    Admitted,
+    TimedOut,
+    Cancelled,
+}
+
+#[derive(Debug, serde::Serialize)]
+pub struct WaitlistStatus {
+    pub id: mtid::Ttid,
+    pub state: WaitlistState,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub position: Option<usize>, // 1 is next in line, only while waiting
+}
+
+impl SocketResponse for WaitlistStatus {
+    fn opcode(&self) -> Opcode {
+        Opcode::WaitlistStatus
+    }
+}
+
+#[derive(Debug, serde::Deserialize, serde::Serialize)]
+pub struct CreateInvite {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub room: Option<mtid::Ttid>, // the first private room joined if not provided
+    #[serde(default)]
+    pub token: Option<String>, // only sent back
+    pub expires_in: Option<u64>, // seconds, an hour if not provided
+    pub max_uses: Option<u32>,   // unlimited if not provided
+}
+
+impl SocketResponse for CreateInvite {
+    fn opcode(&self) -> Opcode {
+        Opcode::CreateInvite
+    }
+}
+
+#[derive(Debug, serde::Deserialize, serde::Serialize)]
+pub struct RevokeInvite {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub room: Option<mtid::Ttid>, // the first owned room if not provided
+    pub token: Option<String>, // every invite to the room if not provided
+    #[serde(default)]
+    pub revoked: usize, // only sent back
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub code: Option<String>, // only sent back, the room's new word code after revoking everything
+}
+
+impl SocketResponse for RevokeInvite {
+    fn opcode(&self) -> Opcode {
+        Opcode::RevokeInvite
+    }
+}
+
+#[derive(Debug, serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum JoinFailure {
+    InviteRequired, // a private room's id only works for people who've been inside
+    Full,
+    Locked,
+    Closed,
+}
+
+impl From<room::JoinError> for JoinFailure {
+    fn from(error: room::JoinError) -> Self {
+        match error {
+            room::JoinError::Full => Self::Full,
+            room::JoinError::Locked => Self::Locked,
+            room::JoinError::Closed => Self::Closed,
+        }
+    }
+}
+
+#[derive(Debug, serde::Serialize)]
+pub struct JoinFailed {
+    pub id: String, // whatever was asked for
+    pub reason: JoinFailure,
+}
+
+impl SocketResponse for JoinFailed {
+    fn opcode(&self) -> Opcode {
+        Opcode::JoinFailed
+    }
+}
+
+#[derive(Debug, serde::Deserialize)]
+pub struct Knock {
+    pub id: String, // room id or word code
+    #[serde(default)]
+    pub keep_others: bool, // stay in the rooms already joined instead of leaving them
+}
+
+impl SocketResponse for Knock {
+    fn opcode(&self) -> Opcode {
+        Opcode::Knock
+    }
+}
+
+#[derive(Debug, serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum KnockState {
+    Pending,
+    Approved,
+    Denied,
+    Expired,
+    QueueFull,
+}
+
+#[derive(Debug, serde::Serialize)]
+pub struct KnockStatus {
+    pub id: mtid::Ttid,
+    pub state: KnockState,
+}
+
+impl SocketResponse for KnockStatus {
+    fn opcode(&self) -> Opcode {
+        Opcode::KnockStatus
+    }
+}
+
+#[derive(Debug, serde::Serialize)]
+pub struct KnockPrompt {
+    pub id: mtid::Ttid,
+    pub persona: MessagePersona, // who's knocking, answer with `AnswerKnock`
+}
+
+impl SocketResponse for KnockPrompt {
+    fn opcode(&self) -> Opcode {
+        Opcode::KnockPrompt
+    }
+}
+
+#[derive(Debug, serde::Deserialize)]
+pub struct AnswerKnock {
+    pub persona_id: uuid::Uuid,
+    pub approve: bool,
+}
+
+impl SocketResponse for AnswerKnock {
+    fn opcode(&self) -> Opcode {
+        Opcode::AnswerKnock
+    }
+}
+
+#[derive(Debug, serde::Deserialize, serde::Serialize)]
+pub struct DirectMessage {
+    pub persona_id: uuid::Uuid, // always who it's for, both when sending and receiving
+    pub message: String,
+    pub drawing: Option<String>,
+    #[serde(default, skip_deserializing, skip_serializing_if = "Option::is_none")]
+    pub persona: Option<MessagePersona>, // only sent back, who it's from
+}
+
+impl SocketResponse for DirectMessage {
+    fn opcode(&self) -> Opcode {
+        Opcode::DirectMessage
+    }
+}
+
+impl DirectMessage {
+    pub fn new(persona_id: uuid::Uuid, message: room::RoomMessage) -> Self {
+        Self {
+            persona_id,
+            message: message.message,
+            drawing: message.drawing,
+            persona: Some(message.persona),
+        }
+    }
+}
+
+#[derive(Debug, serde::Deserialize, serde::Serialize)]
+pub struct DirectMessagePolicy {
+    pub policy: session::DirectMessagePolicy,
+}
+
+impl SocketResponse for DirectMessagePolicy {
+    fn opcode(&self) -> Opcode {
+        Opcode::DirectMessagePolicy
+    }
+}
+
+#[derive(Debug, serde::Deserialize, serde::Serialize)]
+pub struct Ignore {
+    pub persona_id: uuid::Uuid,
+    #[serde(default)]
+    pub ignored: Vec<uuid::Uuid>, // only sent back, everyone ignored now
+}
+
+impl SocketResponse for Ignore {
+    fn opcode(&self) -> Opcode {
+        Opcode::Ignore
+    }
+}
+
+#[derive(Debug, serde::Deserialize, serde::Serialize)]
+pub struct Unignore {
+    pub persona_id: uuid::Uuid,
+    #[serde(default)]
+    pub ignored: Vec<uuid::Uuid>, // only sent back, everyone still ignored
+}
+
+impl SocketResponse for Unignore {
+    fn opcode(&self) -> Opcode {
+        Opcode::Unignore
+    }
+}
+
+#[derive(Debug, serde::Deserialize)]
+pub struct DeleteMessage {
+    pub id: u64,
+    #[serde(default)]
+    pub room: Option<mtid::Ttid>, // the first joined room if not provided, moderators can use any
+}
+
+impl SocketResponse for DeleteMessage {
+    fn opcode(&self) -> Opcode {
+        Opcode::DeleteMessage
+    }
+}
+
+#[derive(Debug, serde::Serialize)]
+pub struct MessageDeleted {
+    pub id: u64,
+    pub room: mtid::Ttid,
+}
+
+impl SocketResponse for MessageDeleted {
+    fn opcode(&self) -> Opcode {
+        Opcode::MessageDeleted
+    }
+}
+
+#[derive(Debug, serde::Deserialize)]
+pub struct React {
+    pub id: u64,
+    pub stamp: String, // one of the stamps in the server settings
+    #[serde(default)]
+    pub remove: bool, // take back a stamp given earlier
+    #[serde(default)]
+    pub room: Option<mtid::Ttid>, // the first joined room if not provided
+}
+
+impl SocketResponse for React {
+    fn opcode(&self) -> Opcode {
+        Opcode::React
+    }
+}
+
+#[derive(Debug, serde::Serialize)]
+pub struct Reaction {
+    pub room: mtid::Ttid,
+    pub id: u64,
+    pub stamp: String,
+    pub persona_id: uuid::Uuid,
+    pub added: bool,
+    pub count: usize, // total for that stamp on the message, apply instead of adding up deltas
+}
+
+impl SocketResponse for Reaction {
+    fn opcode(&self) -> Opcode {
+        Opcode::Reaction
+    }
+}
+
+/// Sent on top of the `EchoMessage` to whoever got mentioned, so clients can ping even when
+/// the chat isn't on screen.
+#[derive(Debug, serde::Serialize)]
+pub struct Mention {
+    pub room: mtid::Ttid,
+    pub id: u64,                 // of the message
+    pub persona: MessagePersona, // who mentioned them
+    pub message: String,
+}
+
+impl SocketResponse for Mention {
+    fn opcode(&self) -> Opcode {
+        Opcode::Mention
+    }
+}
+
 // #[derive(Debug, serde::Deserialize)]
 // pub struct CreateRoom;
 
@@ -1,23 +1,27 @@
 use std::{
-    str::FromStr,
-    sync::{Arc, Mutex, atomic::AtomicUsize},
+    collections::{HashSet, VecDeque},
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicBool, AtomicU64, AtomicUsize},
+    },
+    time::{Duration, Instant},
 };
 
 use rand::Rng;
-use tokio::sync::broadcast;
+use tokio::sync::{Notify, broadcast};
 
-use crate::responses;
-
-const ROOM_MAX_CONNECTIONS: usize = 32;
+use crate::{
+    filter::{ContentFilter, Verdict},
+    identity, responses, room_code,
+    session::{Session, SessionEvent},
+    settings::PublicRoomSettings,
+};
 
-macro_rules! ttid {
-    ($ttid:expr) => {{
-        match mtid::Ttid::from_str($ttid) {
-            Ok(u) => u,
-            Err(_) => panic!("invalid Ttid"),
-        }
-    }};
-}
+pub const ROOM_MAX_CONNECTIONS: usize = 32;
+pub const ROOM_MAX_SPECTATORS: usize = 64;
+pub const ROOM_HISTORY_LEN: usize = 50; // drawings are a few kb each, so not too many
+const PARENT_PREVIEW_LEN: usize = 40;
+const MAX_MENTIONS: usize = 8; // past this it's not a mention, it's spam
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, std::hash::Hash)]
 pub struct RoomId(mtid::Ttid);
@@ -29,15 +33,6 @@ impl Default for RoomId {
 }
 
 impl RoomId {
-    pub fn default_public() -> Vec<Self> {
-        vec![
-            Self(ttid!("0vt-5aw-m0y")),
-            Self(ttid!("njj-67c-hjx")),
-            Self(ttid!("x95-2jt-697")),
-            Self(ttid!("3q5-2wc-332")),
-        ]
-    }
-
     pub fn id(&self) -> mtid::Ttid {
         self.0
     }
@@ -53,24 +48,82 @@ impl From<mtid::Ttid> for RoomId {
     }
 }
 
-pub type RoomTx = broadcast::Sender<RoomMessage>;
-pub type RoomRx = broadcast::Receiver<RoomMessage>;
+pub type RoomTx = broadcast::Sender<RoomEvent>;
+pub type RoomRx = broadcast::Receiver<RoomEvent>;
+
+/// Everything that goes out to a room's subscribers.
+#[derive(Debug, Clone)]
+pub enum RoomEvent {
+    Message(RoomMessage),
+    Deleted {
+        id: u64,
+    }, // tombstone, clients should remove or blur the message
+    Reacted {
+        id: u64,
+        stamp: String,
+        persona: uuid::Uuid,
+        added: bool,
+        count: usize, // how many of that stamp the message has now
+    },
+}
+
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct ReactionCount {
+    pub stamp: String,
+    pub count: usize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReactError {
+    Gone,      // not in history anymore
+    Limit,     // already used all the stamps they get on that message
+    Unchanged, // reacted twice with the same stamp, or took back one they never gave
+}
+
+#[derive(Debug)]
+struct HistoryEntry {
+    message: RoomMessage,
+    reactions: Vec<(String, Vec<uuid::Uuid>)>, // who used each stamp, in the order they were first used
+}
+
+impl HistoryEntry {
+    fn reaction_counts(&self) -> Vec<ReactionCount> {
+        self.reactions
+            .iter()
+            .map(|(stamp, by)| ReactionCount {
+                stamp: stamp.clone(),
+                count: by.len(),
+            })
+            .collect()
+    }
+}
 
 #[derive(Debug)]
 pub struct RoomSubscription {
     pub rx: RoomRx,
     pub room: Room,
     pub persona: Arc<Mutex<Persona>>,
+    pub spectator: bool, // read only, not one of the room's members
+    pub backlog: Vec<(RoomMessage, Vec<ReactionCount>)>, // history from before joining, replayed once
 }
 
 impl Drop for RoomSubscription {
     fn drop(&mut self) {
+        if self.spectator {
+            self.room
+                .spectators
+                .fetch_sub(1, std::sync::atomic::Ordering::Relaxed);
+            self.room.changed.notify_one();
+            return;
+        }
+
         tracing::debug!(
             "decrementing current active connections for room {}",
             self.room.id.id()
         );
 
         self.room.dec_active_connections();
+        self.room.changed.notify_one();
 
         let mut personas = self.room.personas.lock().unwrap();
         let persona_id = self.persona.lock().unwrap().id;
@@ -79,18 +132,72 @@ impl Drop for RoomSubscription {
             let v_id = v.lock().unwrap().id;
             v_id != persona_id
         });
+
+        let mut owner = self.room.owner.lock().unwrap();
+        if *owner == Some(persona_id) {
+            // personas are kept in join order, so the first one has been here the longest
+            let heir = personas.first().map(|v| v.lock().unwrap().clone());
+            *owner = heir.as_ref().map(|v| v.id);
+
+            if let Some(heir) = heir {
+                tracing::debug!(
+                    "passing ownership of room {} to {}",
+                    self.room.id.id(),
+                    heir.id
+                );
+                let _ = self.room.send(RoomMessage::system(
+                    format!("{} is now the room owner", heir.name),
+                    None,
+                ));
+            }
+        }
+        drop(owner);
+        drop(personas);
+
+        self.room.wake_waitlist();
+    }
+}
+
+/// A place in a room's waitlist, given up when dropped.
+#[derive(Debug)]
+pub struct WaitlistTicket {
+    pub room: Room,
+    id: uuid::Uuid,
+}
+
+impl Drop for WaitlistTicket {
+    fn drop(&mut self) {
+        let mut waitlist = self.room.waitlist.lock().unwrap();
+        let was_first = waitlist.front().is_some_and(|s| s.id == self.id);
+        waitlist.retain(|s| s.id != self.id);
+        drop(waitlist);
+
+        // whoever is behind might be able to get in now
+        if was_first {
+            self.room.wake_waitlist();
+        }
+    }
+}
+
+impl WaitlistTicket {
+    /// 1 for the front of the queue, none once admitted.
+    pub fn position(&self) -> Option<usize> {
+        self.room
+            .waitlist
+            .lock()
+            .unwrap()
+            .iter()
+            .position(|s| s.id == self.id)
+            .map(|p| p + 1)
     }
 }
 
 impl RoomSubscription {
-    pub fn send(
-        &self,
-        message: RoomMessage,
-    ) -> Result<usize, broadcast::error::SendError<RoomMessage>> {
-        self.room.tx.send(message)
+    pub fn send(&self, message: RoomMessage) -> Option<u64> {
+        self.room.send(message)
     }
 
-    pub async fn recv(&mut self) -> Result<RoomMessage, broadcast::error::RecvError> {
+    pub async fn recv(&mut self) -> Result<RoomEvent, broadcast::error::RecvError> {
         self.rx.recv().await
     }
 
@@ -100,8 +207,8 @@ impl RoomSubscription {
             format!("{} joined the room", persona.name),
             Some(HELLO_DRAWING.to_string()),
         )) {
-            Ok(_) => {}
-            Err(broadcast::error::SendError(_)) => {
+            Some(_) => {}
+            None => {
                 tracing::error!("failed to send hello message to room {}", self.room.id.id());
             }
         }
@@ -114,24 +221,90 @@ impl RoomSubscription {
         ));
     }
 
-    pub async fn send_invite(&mut self, room_id: RoomId) {
+    pub async fn send_invite(&mut self, code: &str) {
         let _ = self.send(RoomMessage::system(
-            format!("Created a new room! Your room code is {} !", room_id.id()),
+            format!("Created a new room! Your room code is {code} !"),
             Some(INVITE_DRAWING.to_string()),
         ));
     }
 }
 
+// how long invites last if the one minting them doesn't say, and the most they can ask for
+pub const INVITE_DEFAULT_EXPIRY: Duration = Duration::from_secs(60 * 60);
+pub const INVITE_MAX_EXPIRY: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+/// A revocable way into a private room, so the room id itself doesn't have to be handed out.
+#[derive(Debug, Clone)]
+pub struct Invite {
+    pub token: String,
+    pub room: RoomId,
+    pub created_by: uuid::Uuid,
+    pub expires_at: Instant,
+    pub max_uses: Option<u32>, // unlimited if none
+    pub uses: u32,
+}
+
+impl Invite {
+    pub fn new(
+        room: RoomId,
+        created_by: uuid::Uuid,
+        expires_in: Duration,
+        max_uses: Option<u32>,
+    ) -> Self {
+        Self {
+            token: room_code::generate_token(),
+            room,
+            created_by,
+            expires_at: Instant::now() + expires_in.min(INVITE_MAX_EXPIRY),
+            max_uses,
+            uses: 0,
+        }
+    }
+
+    pub fn is_expired(&self) -> bool {
+        self.expires_at <= Instant::now()
+    }
+
+    pub fn is_valid(&self) -> bool {
+        !self.is_expired() && self.max_uses.is_none_or(|max| self.uses < max)
+    }
+}
+
+// knocks nobody answered are dropped after this, and a room only keeps so many at once
+pub const KNOCK_TIMEOUT: Duration = Duration::from_secs(60);
+pub const KNOCK_QUEUE_MAX: usize = 8;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum JoinError {
+    Full,
+    Locked,
+    Closed, // went away between finding it and getting in, like a merged away overflow instance
+}
+
 #[derive(Debug, Clone)]
 pub struct Room {
     pub id: RoomId,
-    pub name: String, // generated only for public rooms, not for private ones. practically for show
+    name: Arc<Mutex<String>>, // generated only for public rooms, private ones can be renamed by the owner
     pub active_connections: Arc<AtomicUsize>,
     pub tx: RoomTx,
     pub max_connections: usize,
+    spectators: Arc<AtomicUsize>,
+    pub max_spectators: usize, // 0 for private rooms, nobody gets to watch those
     pub is_public: bool,
     pub index: Option<usize>, // only for public rooms, indicates the order to display them lmao
+    pub topic: Option<String>, // only for public rooms
+    pub parent: Option<RoomId>, // only for overflow instances of a public room
+    pub instance: usize,      // 1 for the room itself, 2 and up for its overflow instances
+    code: Arc<Mutex<Option<String>>>, // word code for private rooms, if they're turned on
     pub personas: Arc<Mutex<Vec<Arc<Mutex<Persona>>>>>, // oh god WHAT HAVE I DONE
+    owner: Arc<Mutex<Option<uuid::Uuid>>>, // only for private rooms, the session that has the say
+    locked: Arc<AtomicBool>,
+    waitlist: Arc<Mutex<VecDeque<Arc<Session>>>>, // first come first served once the room is full
+    knocks: Arc<Mutex<Vec<(uuid::Uuid, Instant)>>>, // people asking to get into a locked room, until when
+    members: Arc<Mutex<HashSet<uuid::Uuid>>>, // everyone who's been inside a private room, they can come back by id
+    history: Arc<Mutex<VecDeque<HistoryEntry>>>, // the last messages people sent, oldest first
+    next_message_id: Arc<AtomicU64>,
+    changed: Arc<Notify>, // pokes the lobby whenever the population changes
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
@@ -144,12 +317,18 @@ pub struct Persona {
 
 impl Persona {
     pub fn random_color() -> String {
-        let color = random_color::RandomColor {
-            luminosity: Some(random_color::options::Luminosity::Light),
-            ..Default::default()
+        loop {
+            let color = random_color::RandomColor {
+                luminosity: Some(random_color::options::Luminosity::Light),
+                ..Default::default()
+            }
+            .to_rgb_array();
+            let color = format!("{:02X}{:02X}{:02X}FF", color[0], color[1], color[2]);
+
+            if !identity::is_system_like_color(&color) {
+                return color;
+            }
         }
-        .to_rgb_array();
-        format!("{:02X}{:02X}{:02X}FF", color[0], color[1], color[2])
     }
     pub fn new(id: uuid::Uuid) -> Self {
         let numbers: Vec<String> = (0..12)
@@ -166,7 +345,11 @@ impl Persona {
         }
     }
 
-    pub fn from_response(response: responses::Persona, current_persona: Persona) -> Self {
+    pub fn from_response(
+        response: responses::Persona,
+        current_persona: Persona,
+        filter: &ContentFilter,
+    ) -> Self {
         let mut name = response
             .name
             .clone()
@@ -178,17 +361,19 @@ impl Persona {
 
         if response.name.as_ref().is_some_and(|x| x.is_empty())
             || response.name.as_ref().is_some_and(|x| x.len() < 3)
-            // yeaah shitty filtering
-            || response.name.as_ref().is_some_and(|x| x.to_lowercase().contains("system"))
-            || response.name.as_ref().is_some_and(|x| x.to_lowercase().contains("server"))
-            || response.name.as_ref().is_some_and(|x| x.to_lowercase().contains("admin"))
         {
             name = current_persona.name.clone()
+        } else if response.name.is_some() {
+            name = match filter.check_name(&name) {
+                Verdict::Pass(name) if !identity::is_reserved_name(&name) => name,
+                _ => current_persona.name.clone(),
+            }
         }
 
         if response.color.as_ref().is_some_and(|x| x.is_empty())
             || response.color.as_ref().is_some_and(|x| x.len() <= 6)
-        // hex rrggbbaa
+            // hex rrggbbaa
+            || response.color.as_ref().is_some_and(|x| identity::is_system_like_color(x))
         {
             color = current_persona.color.clone()
         }
@@ -230,21 +415,59 @@ const BYE_DRAWING: &str = "/wD/AP8A/wD/AP8A/wD/AP8A/wD/AP8A/wD/AP8A/wD/AP8A/wD/A
 
 #[derive(Debug, Clone)]
 pub struct RoomMessage {
+    pub id: u64, // assigned by the room when sent, 0 until then
+    pub sent_at: Instant,
     pub persona: MessagePersona,
     pub message: String, // client formats the message into lines
     pub drawing: Option<String>,
+    pub parent: Option<Box<MessageParent>>, // set on replies, boxed since most messages aren't
+    pub mentions: Vec<MessagePersona>,      // members pinged with `@name`
 }
 
 impl RoomMessage {
-    pub fn system(message: String, drawing: Option<String>) -> Self {
+    pub fn new(persona: MessagePersona, message: String, drawing: Option<String>) -> Self {
         Self {
-            persona: MessagePersona {
+            id: 0,
+            sent_at: Instant::now(),
+            persona,
+            message,
+            drawing,
+            parent: None,
+            mentions: Vec::new(),
+        }
+    }
+
+    pub fn system(message: String, drawing: Option<String>) -> Self {
+        Self::new(
+            MessagePersona {
                 id: uuid::Uuid::nil(),
-                name: "System".to_string(),
-                color: "EDA728FF".to_string(),
+                name: identity::SYSTEM_NAME.to_string(),
+                color: identity::SYSTEM_COLOR.to_string(),
             },
             message,
             drawing,
+        )
+    }
+}
+
+/// Just enough of the message being replied to for clients to draw a quote bubble.
+#[derive(Debug, serde::Serialize, Clone)]
+pub struct MessageParent {
+    pub id: u64,
+    pub persona: MessagePersona,
+    pub preview: String, // the start of the message
+    pub has_drawing: bool,
+    pub remix: bool, // the reply's drawing was scribbled on top of the parent's
+}
+
+impl MessageParent {
+    pub fn new(parent: &RoomMessage, remix: bool) -> Self {
+        Self {
+            id: parent.id,
+            persona: parent.persona.clone(),
+            preview: parent.message.chars().take(PARENT_PREVIEW_LEN).collect(),
+            has_drawing: parent.drawing.is_some(),
+            remix,
         }
     }
 }
@@ -254,73 +477,441 @@ impl Room {
         let (tx, _rx) = broadcast::channel(ROOM_MAX_CONNECTIONS); // hardcoded max
         Self {
             id,
-            name,
+            name: Arc::new(Mutex::new(name)),
             active_connections: Arc::new(AtomicUsize::new(0)),
             tx,
             max_connections: ROOM_MAX_CONNECTIONS,
+            spectators: Arc::new(AtomicUsize::new(0)),
+            max_spectators: if is_public { ROOM_MAX_SPECTATORS } else { 0 },
             is_public,
             index,
+            topic: None,
+            parent: None,
+            instance: 1,
+            code: Arc::new(Mutex::new(None)),
             personas: Arc::new(Mutex::new(Vec::new())),
+            owner: Arc::new(Mutex::new(None)),
+            locked: Arc::new(AtomicBool::new(false)),
+            waitlist: Arc::new(Mutex::new(VecDeque::new())),
+            knocks: Arc::new(Mutex::new(Vec::new())),
+            members: Arc::new(Mutex::new(HashSet::new())),
+            history: Arc::new(Mutex::new(VecDeque::new())),
+            next_message_id: Arc::new(AtomicU64::new(1)),
+            changed: Arc::new(Notify::new()),
         }
     }
 
-    pub fn new_private() -> Self {
-        let id = RoomId::new();
+    /// Broadcasts to everyone in the room, giving the message its id and keeping it around in
+    /// the history unless it came from the system. The id comes back if anyone got it.
+    pub fn send(&self, mut message: RoomMessage) -> Option<u64> {
+        // held while sending so history and broadcast agree on the order
+        let mut history = self.history.lock().unwrap();
+        message.id = self
+            .next_message_id
+            .fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+        if !message.persona.id.is_nil() {
+            if history.len() >= ROOM_HISTORY_LEN {
+                history.pop_front();
+            }
+            history.push_back(HistoryEntry {
+                message: message.clone(),
+                reactions: Vec::new(),
+            });
+        }
+        let id = message.id;
+        self.tx.send(RoomEvent::Message(message)).ok().map(|_| id)
+    }
+
+    /// Takes a message out of the history and tells everyone to drop it.
+    pub fn delete_message(&self, id: u64) -> Option<RoomMessage> {
+        let mut history = self.history.lock().unwrap();
+        let index = history.iter().position(|e| e.message.id == id)?;
+        let entry = history.remove(index);
+        let _ = self.tx.send(RoomEvent::Deleted { id });
+        entry.map(|e| e.message)
+    }
+
+    pub fn find_message(&self, id: u64) -> Option<RoomMessage> {
+        self.history
+            .lock()
+            .unwrap()
+            .iter()
+            .find(|e| e.message.id == id)
+            .map(|e| e.message.clone())
+    }
+
+    /// Finds `@name` mentions of the room's members. Names can have spaces, so the longest name
+    /// that fits wins, and everyone going by that name gets mentioned since there's no telling
+    /// which one was meant.
+    pub fn resolve_mentions(&self, message: &str) -> Vec<MessagePersona> {
+        let personas: Vec<Persona> = self
+            .personas
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|p| p.lock().unwrap().clone())
+            .collect();
+
+        let mut mentions: Vec<MessagePersona> = Vec::new();
+        for (at, _) in message.match_indices('@') {
+            let rest = message[at + 1..].to_lowercase();
+            let Some(name) = personas
+                .iter()
+                .map(|p| p.name.to_lowercase())
+                .filter(|name| {
+                    rest.strip_prefix(name.as_str())
+                        .is_some_and(|after| !after.starts_with(char::is_alphanumeric))
+                })
+                .max_by_key(|name| name.len())
+            else {
+                continue;
+            };
+
+            for persona in personas.iter().filter(|p| p.name.to_lowercase() == name) {
+                if mentions.len() >= MAX_MENTIONS {
+                    return mentions;
+                }
+                if !mentions.iter().any(|m| m.id == persona.id) {
+                    mentions.push(MessagePersona::from_persona(persona));
+                }
+            }
+        }
+        mentions
+    }
+
+    /// Adds or takes back a stamp on a message in history, letting everyone know the new count.
+    /// Nobody gets more than `max_per_user` different stamps on the same message.
+    pub fn react(
+        &self,
+        id: u64,
+        stamp: &str,
+        persona: uuid::Uuid,
+        add: bool,
+        max_per_user: usize,
+    ) -> Result<usize, ReactError> {
+        let mut history = self.history.lock().unwrap();
+        let entry = history
+            .iter_mut()
+            .find(|e| e.message.id == id)
+            .ok_or(ReactError::Gone)?;
+
+        let used = entry
+            .reactions
+            .iter()
+            .filter(|(_, by)| by.contains(&persona))
+            .count();
+        let index = entry.reactions.iter().position(|(s, _)| s == stamp);
+        let already = index.is_some_and(|i| entry.reactions[i].1.contains(&persona));
+
+        let count = match (add, index) {
+            (true, _) if already => return Err(ReactError::Unchanged),
+            (true, _) if used >= max_per_user => return Err(ReactError::Limit),
+            (true, Some(i)) => {
+                entry.reactions[i].1.push(persona);
+                entry.reactions[i].1.len()
+            }
+            (true, None) => {
+                entry.reactions.push((stamp.to_string(), vec![persona]));
+                1
+            }
+            (false, Some(i)) if already => {
+                entry.reactions[i].1.retain(|p| *p != persona);
+                let count = entry.reactions[i].1.len();
+                if count == 0 {
+                    entry.reactions.remove(i);
+                }
+                count
+            }
+            (false, _) => return Err(ReactError::Unchanged),
+        };
+
+        let _ = self.tx.send(RoomEvent::Reacted {
+            id,
+            stamp: stamp.to_string(),
+            persona,
+            added: add,
+            count,
+        });
+        Ok(count)
+    }
+
+    /// Subscribes to the room along with a copy of its history, taken together so nothing shows
+    /// up in both or neither.
+    fn listen(&self) -> (RoomRx, Vec<(RoomMessage, Vec<ReactionCount>)>) {
+        let history = self.history.lock().unwrap();
+        let backlog = history
+            .iter()
+            .map(|e| (e.message.clone(), e.reaction_counts()))
+            .collect();
+        (self.tx.subscribe(), backlog)
+    }
+
+    pub fn set_change_notifier(&mut self, changed: Arc<Notify>) {
+        self.changed = changed;
+    }
+
+    pub fn new_private(id: RoomId, owner: uuid::Uuid) -> Self {
         let name = format!("Private Room {}", &id.id().to_string()[..4]);
-        Self::new(id, name, false, None)
+        let room = Self::new(id, name, false, None);
+        *room.owner.lock().unwrap() = Some(owner);
+        room
     }
 
-    pub async fn subscribe(&self, persona: Arc<Mutex<Persona>>) -> Option<RoomSubscription> {
-        if self.current_connections() >= ROOM_MAX_CONNECTIONS {
-            None
-        } else {
-            self.active_connections
-                .fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+    pub fn name(&self) -> String {
+        self.name.lock().unwrap().clone()
+    }
+
+    pub fn rename(&self, name: String) {
+        *self.name.lock().unwrap() = name;
+    }
+
+    pub fn code(&self) -> Option<String> {
+        self.code.lock().unwrap().clone()
+    }
+
+    pub fn set_code(&self, code: Option<String>) {
+        *self.code.lock().unwrap() = code;
+    }
+
+    pub fn owner(&self) -> Option<uuid::Uuid> {
+        *self.owner.lock().unwrap()
+    }
+
+    pub fn is_owner(&self, id: uuid::Uuid) -> bool {
+        self.owner() == Some(id)
+    }
+
+    pub fn set_owner(&self, id: uuid::Uuid) {
+        *self.owner.lock().unwrap() = Some(id);
+    }
 
-            let mut personas = self
+    pub fn is_locked(&self) -> bool {
+        self.locked.load(std::sync::atomic::Ordering::Relaxed)
+    }
+
+    pub fn set_locked(&self, locked: bool) {
+        self.locked
+            .store(locked, std::sync::atomic::Ordering::Relaxed);
+    }
+
+    /// Who gets asked about knocks, the owner if there is one and everyone inside otherwise.
+    pub fn gatekeepers(&self) -> Vec<uuid::Uuid> {
+        match self.owner() {
+            Some(owner) => vec![owner],
+            None => self
                 .personas
-                .try_lock()
-                .expect("couldn't lock room's personas");
-
-            let name = persona
-                .try_lock()
-                .expect("failed to lock persona's name")
-                .name
-                .clone();
-            let collisions = personas
+                .lock()
+                .unwrap()
                 .iter()
-                .any(|v| v.try_lock().expect("failed to lock room's persona").name == name);
+                .map(|p| p.lock().unwrap().id)
+                .collect(),
+        }
+    }
 
-            if collisions {
-                persona.lock().unwrap().forced_color = Some(Persona::random_color());
+    pub fn is_gatekeeper(&self, id: uuid::Uuid) -> bool {
+        self.owner()
+            .map_or_else(|| self.has_persona(id), |owner| owner == id)
+    }
+
+    /// Records a knock, false if too many people are already knocking.
+    pub fn knock(&self, id: uuid::Uuid) -> bool {
+        let mut knocks = self.knocks.lock().unwrap();
+        let now = Instant::now();
+        knocks.retain(|(knocker, until)| *until > now && *knocker != id);
+
+        if knocks.len() >= KNOCK_QUEUE_MAX {
+            return false;
+        }
+        knocks.push((id, now + KNOCK_TIMEOUT));
+        true
+    }
+
+    /// Takes back a knock, true if it was still waiting for an answer.
+    pub fn take_knock(&self, id: uuid::Uuid) -> bool {
+        let mut knocks = self.knocks.lock().unwrap();
+        let pending = knocks
+            .iter()
+            .any(|(knocker, until)| *knocker == id && *until > Instant::now());
+        knocks.retain(|(knocker, _)| *knocker != id);
+        pending
+    }
+
+    /// Whether a private room's id is enough for someone to get in, which it is for the owner and
+    /// anyone who's been inside. Everyone else needs the word code or an invite.
+    pub fn admits_by_id(&self, id: uuid::Uuid) -> bool {
+        self.is_public || self.is_owner(id) || self.members.lock().unwrap().contains(&id)
+    }
+
+    /// Someone who got kicked out shouldn't get back in by id.
+    pub fn forget_member(&self, id: uuid::Uuid) {
+        self.members.lock().unwrap().remove(&id);
+    }
+
+    /// Only the people inside right now keep getting in by id.
+    pub fn forget_former_members(&self) {
+        let current: HashSet<uuid::Uuid> = self
+            .personas
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|p| p.lock().unwrap().id)
+            .collect();
+        self.members
+            .lock()
+            .unwrap()
+            .retain(|id| current.contains(id));
+    }
+
+    pub fn has_persona(&self, id: uuid::Uuid) -> bool {
+        self.personas
+            .lock()
+            .unwrap()
+            .iter()
+            .any(|v| v.lock().unwrap().id == id)
+    }
+
+    pub async fn subscribe(
+        &self,
+        persona: Arc<Mutex<Persona>>,
+    ) -> Result<RoomSubscription, JoinError> {
+        self.admit(persona, false)
+    }
+
+    /// Same as subscribing, but an invite gets you past the lock.
+    pub async fn subscribe_invited(
+        &self,
+        persona: Arc<Mutex<Persona>>,
+    ) -> Result<RoomSubscription, JoinError> {
+        self.admit(persona, true)
+    }
+
+    fn admit(
+        &self,
+        persona: Arc<Mutex<Persona>>,
+        invited: bool,
+    ) -> Result<RoomSubscription, JoinError> {
+        if self.is_locked() && !invited {
+            return Err(JoinError::Locked);
+        }
+
+        // nobody gets to skip the queue, the first one waiting takes the next free slot
+        {
+            let persona_id = persona.lock().unwrap().id;
+            let mut waitlist = self.waitlist.lock().unwrap();
+            let first_in_line = waitlist.front().map(|s| s.id);
+            if first_in_line.is_some_and(|id| id != persona_id)
+                || self.current_connections() >= self.max_connections
+            {
+                return Err(JoinError::Full);
+            }
+            if first_in_line == Some(persona_id) {
+                waitlist.pop_front();
             }
+        }
 
-            personas.push(persona.clone());
+        self.active_connections
+            .fetch_add(1, std::sync::atomic::Ordering::Relaxed);
 
-            Some(RoomSubscription {
-                room: self.clone(),
-                rx: self.tx.subscribe(),
-                persona,
-            })
+        let mut personas = self.personas.lock().unwrap();
+
+        let (id, name) = {
+            let persona = persona.lock().unwrap();
+            (persona.id, persona.name.clone())
+        };
+        if !self.is_public {
+            self.members.lock().unwrap().insert(id);
+        }
+        let collisions = personas
+            .iter()
+            .any(|v| identity::is_confusable(&v.lock().unwrap().name, &name));
+
+        if collisions {
+            persona.lock().unwrap().forced_color = Some(Persona::random_color());
+        }
+
+        personas.push(persona.clone());
+        drop(personas);
+        self.changed.notify_one();
+        self.wake_waitlist();
+
+        let (rx, backlog) = self.listen();
+        Ok(RoomSubscription {
+            room: self.clone(),
+            rx,
+            persona,
+            spectator: false,
+            backlog,
+        })
+    }
+
+    /// Watches the room without taking up a member slot or being able to talk.
+    pub fn spectate(&self, persona: Arc<Mutex<Persona>>) -> Result<RoomSubscription, JoinError> {
+        let spectators = self
+            .spectators
+            .fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+        if spectators >= self.max_spectators {
+            self.spectators
+                .fetch_sub(1, std::sync::atomic::Ordering::Relaxed);
+            return Err(JoinError::Full);
+        }
+        self.changed.notify_one();
+
+        let (rx, backlog) = self.listen();
+        Ok(RoomSubscription {
+            room: self.clone(),
+            rx,
+            persona,
+            spectator: true,
+            backlog,
+        })
+    }
+
+    pub fn current_spectators(&self) -> usize {
+        self.spectators.load(std::sync::atomic::Ordering::Relaxed)
+    }
+
+    /// Queues a session up for the next free slot.
+    pub fn enqueue(&self, session: Arc<Session>) -> WaitlistTicket {
+        let id = session.id;
+        let mut waitlist = self.waitlist.lock().unwrap();
+        if !waitlist.iter().any(|s| s.id == id) {
+            waitlist.push_back(session);
+        }
+        drop(waitlist);
+
+        // the room might have emptied out between failing to join and getting in line
+        self.wake_waitlist();
+        WaitlistTicket {
+            room: self.clone(),
+            id,
+        }
+    }
+
+    pub fn waitlist_len(&self) -> usize {
+        self.waitlist.lock().unwrap().len()
+    }
+
+    /// Tells the first one in line there's space, they take it from there.
+    fn wake_waitlist(&self) {
+        if self.current_connections() >= self.max_connections {
+            return;
+        }
+
+        if let Some(first) = self.waitlist.lock().unwrap().front() {
+            first.notify(SessionEvent::SlotFreed { room: self.id });
         }
     }
 
     pub fn check_collisions(&self, persona: Arc<Mutex<Persona>>) {
-        let personas = self
-            .personas
-            .try_lock()
-            .expect("couldn't lock room's personas");
+        let personas = self.personas.lock().unwrap();
 
-        let this_persona = persona
-            .try_lock()
-            .expect("failed to lock persona's name")
-            .clone();
+        let this_persona = persona.lock().unwrap().clone();
         let has_collision = personas
             .iter()
-            .filter(|v| v.try_lock().expect("failed to lock persona").id != this_persona.id) // Exclude self
-            .any(|v| v.try_lock().expect("failed to lock persona").name == this_persona.name);
+            .filter(|v| v.lock().unwrap().id != this_persona.id) // Exclude self
+            .any(|v| identity::is_confusable(&v.lock().unwrap().name, &this_persona.name));
 
-        let mut persona_guard = persona.try_lock().expect("failed to lock persona's name");
+        let mut persona_guard = persona.lock().unwrap();
         if has_collision {
             tracing::debug!("collision found, adding forced color");
             persona_guard.forced_color = Some(Persona::random_color());
@@ -345,15 +936,92 @@ impl Room {
             .load(std::sync::atomic::Ordering::Relaxed)
     }
 
-    pub fn default_public() -> Vec<(RoomId, Self)> {
-        let room_ids = RoomId::default_public();
-        let mut rooms = Vec::new();
-        for (i, id) in room_ids.iter().enumerate() {
-            let name = format!("Public Room {}", i + 1);
-            let room = Room::new(*id, name, true, Some(i));
-            rooms.push((*id, room))
-        }
+    pub fn new_public(settings: &PublicRoomSettings) -> Self {
+        let mut room = Self::new(
+            settings.id.into(),
+            settings.name.clone(),
+            true,
+            Some(settings.index),
+        );
+        room.max_connections = settings.capacity;
+        room.max_spectators = settings.spectator_capacity;
+        room.topic = settings.topic.clone();
+        room
+    }
+
+    /// Another copy of a full public room, "Public Room 1" spills into "Public Room 1 (2)".
+    pub fn new_overflow(id: RoomId, parent: &Room, instance: usize) -> Self {
+        let mut room = Self::new(
+            id,
+            format!("{} ({instance})", parent.name()),
+            true,
+            parent.index,
+        );
+        room.max_connections = parent.max_connections;
+        room.max_spectators = parent.max_spectators;
+        room.topic = parent.topic.clone();
+        room.parent = Some(parent.id);
+        room.instance = instance;
+        room
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn invite(max_uses: Option<u32>) -> Invite {
+        Invite::new(
+            RoomId::new(),
+            uuid::Uuid::new_v4(),
+            INVITE_DEFAULT_EXPIRY,
+            max_uses,
+        )
+    }
+
+    #[test]
+    fn invites_run_out_of_uses() {
+        let mut invite = invite(Some(2));
+        assert!(invite.is_valid());
+        invite.uses = 1;
+        assert!(invite.is_valid());
+        invite.uses = 2;
+        assert!(!invite.is_valid());
+        assert!(!invite.is_expired());
+    }
+
+    #[test]
+    fn unlimited_invites_only_expire() {
+        let mut invite = invite(None);
+        invite.uses = u32::MAX;
+        assert!(invite.is_valid());
+
+        invite.expires_at = Instant::now();
+        assert!(invite.is_expired());
+        assert!(!invite.is_valid());
+    }
+
+    #[test]
+    fn invite_expiry_is_capped() {
+        let invite = Invite::new(RoomId::new(), uuid::Uuid::new_v4(), Duration::MAX, None);
+        assert!(invite.expires_at <= Instant::now() + INVITE_MAX_EXPIRY);
+    }
+
+    #[test]
+    fn former_members_get_back_in_by_id() {
+        let owner = uuid::Uuid::new_v4();
+        let room = Room::new_private(RoomId::new(), owner);
+        let id = uuid::Uuid::new_v4();
+        assert!(!room.admits_by_id(id));
+
+        let subscription = room
+            .admit(Arc::new(Mutex::new(Persona::new(id))), false)
+            .unwrap();
+        drop(subscription);
+        assert!(room.admits_by_id(id));
 
-        rooms
+        room.forget_former_members();
+        assert!(!room.admits_by_id(id));
+        assert!(room.admits_by_id(owner));
     }
 }
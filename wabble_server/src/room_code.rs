@@ -0,0 +1,101 @@
+use std::str::FromStr;
+
+use rand::{Rng, seq::IndexedRandom};
+
+// short and hard to mishear, these get read out loud over voice chat
+const ADJECTIVES: &[&str] = &[
+    "amber", "brave", "bright", "calm", "clever", "cosmic", "cozy", "crisp", "dizzy", "eager",
+    "fancy", "fluffy", "fuzzy", "gentle", "giddy", "golden", "happy", "hidden", "jolly", "lucky",
+    "mellow", "mighty", "misty", "noble", "odd", "orange", "plucky", "polite", "purple", "quick",
+    "quiet", "rapid", "rosy", "rusty", "shiny", "silly", "sleepy", "snowy", "sunny", "swift",
+    "tiny", "velvet", "witty", "zesty",
+];
+
+const ANIMALS: &[&str] = &[
+    "badger", "beaver", "bison", "camel", "cobra", "crane", "crow", "dingo", "dolphin", "eagle",
+    "ferret", "finch", "gecko", "goose", "hamster", "heron", "koala", "lemur", "llama", "lynx",
+    "magpie", "marmot", "moose", "newt", "otter", "owl", "panda", "parrot", "pelican", "penguin",
+    "puffin", "rabbit", "raven", "seal", "sloth", "squid", "tapir", "toad", "turtle", "walrus",
+    "wombat", "yak", "zebra",
+];
+
+/// Reads a ttid typed in without caring about case or separators, `0VT 5AW M0Y` works too.
+pub fn parse_id(code: &str) -> Option<mtid::Ttid> {
+    let normalized = normalize(code);
+    if normalized.len() != 9 {
+        return None;
+    }
+    mtid::Ttid::from_str(&normalized).ok()
+}
+
+/// Something like `purple-otter-42`.
+pub fn generate() -> String {
+    let mut rng = rand::rng();
+    format!(
+        "{}-{}-{}",
+        ADJECTIVES.choose(&mut rng).unwrap(),
+        ANIMALS.choose(&mut rng).unwrap(),
+        rng.random_range(10..100)
+    )
+}
+
+/// `Purple Otter 42`, `purple_otter-42` and `purpleotter42` all end up as `purpleotter42`.
+pub fn normalize(code: &str) -> String {
+    code.chars()
+        .filter(|c| c.is_ascii_alphanumeric())
+        .map(|c| c.to_ascii_lowercase())
+        .collect()
+}
+
+/// Invite tokens are secret, so they're long and random instead of readable.
+pub fn generate_token() -> String {
+    const ALPHABET: &[u8] = b"abcdefghjkmnpqrstuvwxyz23456789"; // no lookalikes
+    let mut rng = rand::rng();
+    (0..16)
+        .map(|_| *ALPHABET.choose(&mut rng).unwrap() as char)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ids_parse_loosely() {
+        let id = parse_id("0vt-5aw-m0y").unwrap();
+        assert_eq!(parse_id("0VT 5AW M0Y"), Some(id));
+        assert_eq!(parse_id("0vt5awm0y"), Some(id));
+        assert_eq!(parse_id("0vt-5aw"), None);
+        assert_eq!(parse_id("purple-otter-42"), None);
+    }
+
+    #[test]
+    fn codes_normalize_the_same() {
+        assert_eq!(normalize("Purple Otter 42"), "purpleotter42");
+        assert_eq!(normalize("purple_otter-42"), "purpleotter42");
+        assert_eq!(normalize("purpleotter42"), "purpleotter42");
+    }
+
+    #[test]
+    fn generated_codes_are_readable() {
+        for _ in 0..100 {
+            let code = generate();
+            let parts: Vec<_> = code.split('-').collect();
+            assert_eq!(parts.len(), 3, "{code}");
+            assert!(ADJECTIVES.contains(&parts[0]), "{code}");
+            assert!(ANIMALS.contains(&parts[1]), "{code}");
+            assert!(
+                (10..100).contains(&parts[2].parse::<u32>().unwrap()),
+                "{code}"
+            );
+        }
+    }
+
+    #[test]
+    fn tokens_are_long_and_unambiguous() {
+        let token = generate_token();
+        assert_eq!(token.len(), 16);
+        assert!(!token.contains(['l', 'i', 'o', '0', '1']));
+        assert_ne!(token, generate_token());
+    }
+}
@@ -0,0 +1,281 @@
+use std::{
+    collections::HashSet,
+    net::IpAddr,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicBool, Ordering},
+    },
+    time::{Duration, Instant, SystemTime},
+};
+
+use dashmap::DashMap;
+use tokio::sync::mpsc;
+use uuid::Uuid;
+
+use crate::{
+    room::{MessagePersona, Persona, RoomId, RoomMessage},
+    room_code,
+};
+
+// plenty for hiding a few trolls, and keeps the list from growing forever
+pub const IGNORE_LIST_MAX: usize = 256;
+// how long a dropped session can be picked back up with its resume token
+pub const RESUME_WINDOW: Duration = Duration::from_secs(300);
+
+pub type SessionTx = mpsc::UnboundedSender<SessionEvent>;
+pub type SessionRx = mpsc::UnboundedReceiver<SessionEvent>;
+
+/// Things other sessions (or the server itself) want a socket to do.
+#[derive(Debug, Clone)]
+pub enum SessionEvent {
+    Kicked {
+        room: Option<RoomId>, // any room if none
+        reason: Option<String>,
+    },
+    Muted {
+        duration: Duration,
+        reason: Option<String>,
+    },
+    Banned {
+        duration: Option<Duration>,
+        reason: Option<String>,
+    },
+    SlotFreed {
+        room: RoomId, // sent to whoever is first in the room's waitlist
+    },
+    Knocked {
+        room: RoomId, // sent to whoever can let people into a locked room
+        persona: MessagePersona,
+    },
+    KnockAnswered {
+        room: RoomId,
+        approved: bool,
+    },
+    DirectMessage {
+        message: RoomMessage, // already filtered, straight from the sender
+    },
+    Mentioned {
+        room: RoomId,
+        message: RoomMessage, // the message doing the mentioning, with its id
+    },
+}
+
+/// Who gets to send someone direct messages.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DirectMessagePolicy {
+    Nobody,
+    #[default]
+    SharedRoom, // anyone they're currently in a room with
+    Anyone,
+}
+
+/// The shared half of a socket connection, reachable by anyone through the global state.
+#[derive(Debug)]
+pub struct Session {
+    pub id: Uuid,
+    pub ip: IpAddr,
+    pub persona: Arc<Mutex<Persona>>,
+    pub connected_at: SystemTime,
+    pub resume_token: String, // secret, only ever sent to the session itself
+    tx: SessionTx,
+    moderator: AtomicBool,
+    dm_policy: Mutex<DirectMessagePolicy>,
+    ignored: Mutex<HashSet<Uuid>>, // persona ids
+}
+
+impl Session {
+    pub fn new(id: Uuid, ip: IpAddr, persona: Arc<Mutex<Persona>>) -> (Self, SessionRx) {
+        let (tx, rx) = mpsc::unbounded_channel();
+        let session = Self {
+            id,
+            ip,
+            persona,
+            connected_at: SystemTime::now(),
+            resume_token: room_code::generate_token(),
+            tx,
+            moderator: AtomicBool::new(false),
+            dm_policy: Mutex::new(DirectMessagePolicy::default()),
+            ignored: Mutex::new(HashSet::new()),
+        };
+
+        (session, rx)
+    }
+
+    pub fn notify(&self, event: SessionEvent) {
+        if self.tx.send(event).is_err() {
+            tracing::debug!("session {} is gone, dropping event", self.id);
+        }
+    }
+
+    pub fn is_moderator(&self) -> bool {
+        self.moderator.load(Ordering::Relaxed)
+    }
+
+    pub fn set_moderator(&self, moderator: bool) {
+        self.moderator.store(moderator, Ordering::Relaxed);
+    }
+
+    pub fn dm_policy(&self) -> DirectMessagePolicy {
+        *self.dm_policy.lock().unwrap()
+    }
+
+    pub fn set_dm_policy(&self, policy: DirectMessagePolicy) {
+        *self.dm_policy.lock().unwrap() = policy;
+    }
+
+    /// False if the list is already full.
+    pub fn ignore(&self, id: Uuid) -> bool {
+        let mut ignored = self.ignored.lock().unwrap();
+        if ignored.len() >= IGNORE_LIST_MAX && !ignored.contains(&id) {
+            return false;
+        }
+        ignored.insert(id);
+        true
+    }
+
+    pub fn unignore(&self, id: Uuid) {
+        self.ignored.lock().unwrap().remove(&id);
+    }
+
+    pub fn is_ignoring(&self, id: Uuid) -> bool {
+        self.ignored.lock().unwrap().contains(&id)
+    }
+
+    pub fn get_ignored(&self) -> Vec<Uuid> {
+        self.ignored.lock().unwrap().iter().copied().collect()
+    }
+
+    /// Picks up where a dropped session left off.
+    pub fn restore(&self, resumed: Resumable) {
+        *self.ignored.lock().unwrap() = resumed.ignored;
+    }
+
+    pub fn name(&self) -> String {
+        self.persona.lock().unwrap().name.clone()
+    }
+}
+
+/// What's left of a session after its socket drops, kept around for a while under its resume
+/// token.
+#[derive(Debug, Clone)]
+pub struct Resumable {
+    pub id: Uuid,
+    pub ignored: HashSet<Uuid>,
+    expires_at: Instant,
+}
+
+impl Resumable {
+    pub fn new(session: &Session) -> Self {
+        Self {
+            id: session.id,
+            ignored: session.ignored.lock().unwrap().clone(),
+            expires_at: Instant::now() + RESUME_WINDOW,
+        }
+    }
+
+    pub fn is_expired(&self) -> bool {
+        self.expires_at <= Instant::now()
+    }
+}
+
+#[derive(Debug)]
+pub struct SessionGuard {
+    session: Arc<Session>,
+    sessions: Arc<DashMap<Uuid, Arc<Session>>>,
+    resumable: Arc<DashMap<String, Resumable>>,
+}
+
+impl Drop for SessionGuard {
+    fn drop(&mut self) {
+        tracing::debug!("unregistering session {}", self.session.id);
+        self.sessions.remove(&self.session.id);
+
+        self.resumable.retain(|_, r| !r.is_expired());
+        self.resumable.insert(
+            self.session.resume_token.clone(),
+            Resumable::new(&self.session),
+        );
+    }
+}
+
+impl SessionGuard {
+    pub fn new(
+        session: &Arc<Session>,
+        sessions: &Arc<DashMap<Uuid, Arc<Session>>>,
+        resumable: &Arc<DashMap<String, Resumable>>,
+    ) -> Self {
+        tracing::debug!("registering session {}", session.id);
+        sessions.insert(session.id, session.clone());
+
+        Self {
+            session: session.clone(),
+            sessions: sessions.clone(),
+            resumable: resumable.clone(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, serde::Serialize, serde::Deserialize)]
+#[serde(tag = "type", content = "value", rename_all = "snake_case")]
+pub enum BanTarget {
+    Session(Uuid), // also keeps its address out, a reconnect would get a new session id
+    Ip(IpAddr),
+}
+
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct Ban {
+    pub target: BanTarget,
+    pub ip: Option<IpAddr>, // the session's address at the time, for session bans
+    pub reason: Option<String>,
+    pub created_at: SystemTime,
+    pub expires_at: Option<SystemTime>, // forever if none
+}
+
+impl Ban {
+    pub fn new(target: BanTarget, duration: Option<Duration>, reason: Option<String>) -> Self {
+        let created_at = SystemTime::now();
+        Self {
+            target,
+            ip: match target {
+                BanTarget::Session(_) => None,
+                BanTarget::Ip(ip) => Some(ip),
+            },
+            reason,
+            created_at,
+            // a duration too long to represent is as good as forever
+            expires_at: duration.and_then(|d| created_at.checked_add(d)),
+        }
+    }
+
+    pub fn is_expired(&self) -> bool {
+        self.expires_at
+            .is_some_and(|expires_at| expires_at <= SystemTime::now())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ban(duration: Option<Duration>) -> Ban {
+        Ban::new(BanTarget::Ip("192.0.2.1".parse().unwrap()), duration, None)
+    }
+
+    #[test]
+    fn ban_expiry() {
+        assert!(!ban(None).is_expired());
+        assert!(!ban(Some(Duration::from_secs(60))).is_expired());
+
+        let mut past = ban(Some(Duration::from_secs(60)));
+        past.expires_at = Some(SystemTime::now() - Duration::from_secs(1));
+        assert!(past.is_expired());
+    }
+
+    #[test]
+    fn overlong_bans_are_permanent() {
+        let ban = ban(Some(Duration::MAX));
+        assert_eq!(ban.expires_at, None);
+        assert!(!ban.is_expired());
+    }
+}
@@ -1,12 +1,12 @@
 use clap::Parser;
 
-use crate::settings::Settings;
+use crate::{filter::WordList, settings::Settings};
 
 /// pretty simple CLI for creating the config file
 #[derive(Parser, Debug)]
 #[command(version, about, long_about = None, author)]
 struct Args {
-    /// Create a new freshly baked config file in the current directory
+    /// Create a new freshly baked config file (and word list) in the current directory
     #[arg(short, long)]
     generate: bool,
 }
@@ -15,6 +15,7 @@ pub fn run() -> anyhow::Result<()> {
     let args = Args::parse();
     if args.generate {
         Settings::create_settings_file()?;
+        WordList::create_file(&Settings::default().filter.word_list)?;
         println!(
             "Settings file created successfully! Check it out before running the app again :)"
         );
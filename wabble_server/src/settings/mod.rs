@@ -1,16 +1,43 @@
-use std::{fs::File, io::Write, net::SocketAddr, path::Path};
+use std::{
+    fs::File,
+    io::Write,
+    net::{IpAddr, SocketAddr},
+    path::{Path, PathBuf},
+    str::FromStr,
+};
 
 use smart_default::SmartDefault;
 
+use crate::room::{ROOM_MAX_CONNECTIONS, ROOM_MAX_SPECTATORS};
+
 pub mod cli;
 
+macro_rules! ttid {
+    ($ttid:expr) => {{
+        match mtid::Ttid::from_str($ttid) {
+            Ok(u) => u,
+            Err(_) => panic!("invalid Ttid"),
+        }
+    }};
+}
+
 #[derive(Debug, serde::Serialize, serde::Deserialize, SmartDefault)]
+#[serde(default)]
 pub struct HttpSettings {
     #[default(SocketAddr::from(([127, 0, 0, 1], 8080)))]
     pub bind: SocketAddr,
+    /// proxies allowed to tell us the real client address via `Forwarded`/`X-Forwarded-For`
+    pub trusted_proxies: Vec<IpAddr>,
+    /// expect a HAProxy PROXY protocol (v1 or v2) header at the start of every connection
+    #[default(false)]
+    pub proxy_protocol: bool,
+    /// seconds to keep serving (with `/readyz` failing) after a shutdown is requested
+    #[default(0)]
+    pub drain_delay: u64,
 }
 
 #[derive(serde::Deserialize, serde::Serialize, Debug, SmartDefault)]
+#[serde(default)]
 pub struct LoggingSettings {
     #[default(true)]
     pub enabled: bool,
@@ -33,10 +60,118 @@ pub enum LoggingSettingsFormat {
     Compact,
 }
 
+#[derive(Debug, serde::Serialize, serde::Deserialize, SmartDefault)]
+#[serde(default)]
+pub struct FilterSettings {
+    /// toml file with the filter rules, the built-in list is used if it doesn't exist
+    #[default(PathBuf::from("filter.toml"))]
+    pub word_list: PathBuf,
+    /// how often (in seconds) the word list is checked for changes
+    #[default(10)]
+    pub reload_interval: u64,
+}
+
+#[derive(Debug, serde::Serialize, serde::Deserialize, Default)]
+#[serde(default)]
+pub struct ModerationSettings {
+    /// secret moderators log in with, moderation is disabled if it isn't set
+    pub secret: Option<String>,
+}
+
 #[derive(Debug, serde::Serialize, serde::Deserialize, Default)]
+#[serde(default)]
+pub struct AdminSettings {
+    /// bearer token for the `/admin` api, the api is disabled if it isn't set
+    pub token: Option<String>,
+}
+
+#[derive(Debug, serde::Serialize, serde::Deserialize, SmartDefault)]
+#[serde(default)]
+pub struct MetricsSettings {
+    /// serve prometheus metrics on their own address, away from the public one
+    #[default(false)]
+    pub enabled: bool,
+    #[default(SocketAddr::from(([127, 0, 0, 1], 9090)))]
+    pub bind: SocketAddr,
+}
+
+#[derive(Debug, serde::Serialize, serde::Deserialize, SmartDefault)]
+#[serde(default)]
+pub struct RoomSettings {
+    /// give private rooms a code like `purple-otter-42` that's easier to read out than the id
+    #[default(false)]
+    pub word_codes: bool,
+    /// how many rooms a single connection can be in (or spectate) at the same time
+    #[default(4)]
+    pub max_subscriptions: usize,
+}
+
+#[derive(Debug, serde::Serialize, serde::Deserialize, SmartDefault)]
+#[serde(default)]
+pub struct ReactionSettings {
+    /// the stamps people can react to messages with, clients map these names to pictures
+    #[default(vec!["heart".into(), "laugh".into(), "wow".into(), "sad".into(), "star".into()])]
+    pub stamps: Vec<String>,
+    /// how many different stamps one person can put on the same message
+    #[default(3)]
+    pub max_per_message: usize,
+}
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct PublicRoomSettings {
+    pub id: mtid::Ttid,
+    pub name: String,
+    #[serde(default = "default_capacity")]
+    pub capacity: usize,
+    /// read only watchers, counted separately from the members above
+    #[serde(default = "default_spectator_capacity")]
+    pub spectator_capacity: usize,
+    /// rooms are listed from the lowest index to the highest
+    pub index: usize,
+    pub topic: Option<String>,
+}
+
+fn default_capacity() -> usize {
+    ROOM_MAX_CONNECTIONS
+}
+
+fn default_spectator_capacity() -> usize {
+    ROOM_MAX_SPECTATORS
+}
+
+fn default_public_rooms() -> Vec<PublicRoomSettings> {
+    [
+        ttid!("0vt-5aw-m0y"),
+        ttid!("njj-67c-hjx"),
+        ttid!("x95-2jt-697"),
+        ttid!("3q5-2wc-332"),
+    ]
+    .into_iter()
+    .enumerate()
+    .map(|(i, id)| PublicRoomSettings {
+        id,
+        name: format!("Public Room {}", i + 1),
+        capacity: ROOM_MAX_CONNECTIONS,
+        spectator_capacity: ROOM_MAX_SPECTATORS,
+        index: i,
+        topic: None,
+    })
+    .collect()
+}
+
+#[derive(Debug, serde::Serialize, serde::Deserialize, SmartDefault)]
+#[serde(default)]
 pub struct Settings {
     pub http: HttpSettings,
     pub logging: LoggingSettings,
+    pub filter: FilterSettings,
+    pub moderation: ModerationSettings,
+    pub admin: AdminSettings,
+    pub metrics: MetricsSettings,
+    pub rooms: RoomSettings,
+    pub reactions: ReactionSettings,
+    #[default(default_public_rooms())]
+    pub public_rooms: Vec<PublicRoomSettings>,
 }
 
 impl Settings {